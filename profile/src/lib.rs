@@ -0,0 +1,111 @@
+use std::{fs::File, io, path::Path, str::FromStr};
+
+use mina_tree::scan_state::{
+    scan_state::ConstraintConstants,
+    currency::{Amount, Fee},
+};
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+
+const BUILTIN: &[(&str, &str)] = &[("berkeley", include_str!("../profiles/berkeley.json"))];
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// Parameters of the network the archive follows.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NetworkProfile {
+    pub name: String,
+    /// Changes with every release of a testnet, so a built-in profile may not have it,
+    /// then it must be given in the command line.
+    pub chain_id: Option<String>,
+    pub seed_peers: Vec<String>,
+    pub gossip_topic: String,
+    pub genesis_timestamp: Option<String>,
+    /// The account that wins the genesis block, it is not in the genesis ledger config.
+    pub genesis_winner: String,
+    pub ledger_depth: u8,
+    pub constraint_constants: Constants,
+}
+
+/// Serializable part of `ConstraintConstants`, amounts are in nanomina.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Constants {
+    pub sub_windows_per_window: u64,
+    pub work_delay: u64,
+    pub block_window_duration_ms: u64,
+    pub transaction_capacity_log_2: u64,
+    pub pending_coinbase_depth: u64,
+    pub coinbase_amount: u64,
+    pub supercharged_coinbase_factor: u64,
+    pub account_creation_fee: u64,
+}
+
+impl NetworkProfile {
+    pub fn builtin(name: &str) -> Option<Self> {
+        let (_, json) = BUILTIN.iter().find(|(n, _)| *n == name)?;
+        Some(serde_json::from_str(json).expect("built-in profile must be valid"))
+    }
+
+    pub fn load<P>(path: P) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        serde_json::from_reader(File::open(path)?).map_err(Into::into)
+    }
+
+    pub fn constraint_constants(&self) -> ConstraintConstants {
+        let c = &self.constraint_constants;
+        ConstraintConstants {
+            sub_windows_per_window: c.sub_windows_per_window as _,
+            ledger_depth: self.ledger_depth as _,
+            work_delay: c.work_delay as _,
+            block_window_duration_ms: c.block_window_duration_ms as _,
+            transaction_capacity_log_2: c.transaction_capacity_log_2 as _,
+            pending_coinbase_depth: c.pending_coinbase_depth as _,
+            coinbase_amount: Amount::from_u64(c.coinbase_amount),
+            supercharged_coinbase_factor: c.supercharged_coinbase_factor as _,
+            account_creation_fee: Fee::from_u64(c.account_creation_fee),
+            fork: None,
+        }
+    }
+}
+
+/// The name of a built-in profile, or the path to the profile file.
+impl FromStr for NetworkProfile {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match Self::builtin(s) {
+            Some(profile) => Ok(profile),
+            None => Self::load(s),
+        }
+    }
+}
+
+/// The position of the blocks of a level in the `blocks` file of the backup,
+/// the backup is written by the tester and imported by the archive.
+#[derive(Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub height: u32,
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NetworkProfile;
+
+    #[test]
+    fn berkeley_supercharges_coinbase() {
+        let constants = NetworkProfile::builtin("berkeley")
+            .unwrap()
+            .constraint_constants();
+        assert_eq!(constants.supercharged_coinbase_factor, 2);
+    }
+}
@@ -1,4 +1,11 @@
-use std::{ops::DerefMut, borrow::Cow, sync::Arc};
+use std::{
+    ops::DerefMut,
+    borrow::Cow,
+    sync::Arc,
+    collections::{BTreeMap, BTreeSet},
+    future,
+    time::Duration,
+};
 
 use libp2p::{
     Swarm,
@@ -12,25 +19,70 @@ use libp2p_rpc_behaviour::{Event, StreamId, Received};
 
 use mina_p2p_messages::{
     binprot::{self, BinProtRead},
-    rpc_kernel::{self, RpcMethod, ResponseHeader, ResponsePayload, QueryHeader},
+    rpc_kernel::{self, RpcMethod, RpcResult, ResponseHeader, ResponsePayload, QueryHeader},
     rpc, v2,
+    core::Info,
 };
 
 use thiserror::Error;
+use tokio::time::{self, Instant};
 
-use crate::db::{BlockHeader, Db};
+use crate::{
+    db::{self, BlockHeader, DbError, Storage},
+    snarked_ledger::SnarkedLedger,
+};
 
 use super::main_loop::{B, BEvent};
 
 pub type TSwarm = Swarm<B>;
 pub type TSwarmEvent = SwarmEvent<BEvent, THandlerErr<B>>;
 
-pub struct Client<S> {
+pub struct Client<S, D> {
     pub swarm: S,
-    peer: Option<PeerId>,
-    stream: Option<StreamId>,
+    peers: BTreeMap<PeerId, PeerState>,
+    pending: BTreeMap<i64, Pending>,
+    // with the instant the response is dropped at unless somebody takes it
+    completed: BTreeMap<i64, (Instant, Result<Vec<u8>, ClientError>)>,
     id: i64,
-    db: Arc<Db>,
+    db: Arc<D>,
+    new_blocks: Vec<(v2::StateHash, v2::MinaBlockBlockStableV2)>,
+    // the ledgers served to the peers, with the instant each was last queried at
+    served_ledgers: BTreeMap<v2::LedgerHash, (Instant, SnarkedLedger)>,
+    ledger_depth: u8,
+}
+
+#[derive(Default)]
+struct PeerState {
+    // outgoing stream, the peer can be queried when it is ready
+    stream: Option<StreamId>,
+    in_flight: usize,
+    // moving average
+    latency: Option<Duration>,
+    successes: u32,
+    failures: u32,
+}
+
+impl PeerState {
+    /// Expected cost of sending one more query to the peer, lower is better.
+    fn cost(&self) -> f64 {
+        let latency = self.latency.unwrap_or(Duration::from_secs(1)).as_secs_f64();
+        let failure_rate = f64::from(self.failures) / f64::from(self.successes + self.failures + 1);
+        latency * (1.0 + 4.0 * failure_rate) * (self.in_flight + 1) as f64
+    }
+
+    fn success(&mut self, elapsed: Duration) {
+        self.successes += 1;
+        self.latency = Some(match self.latency {
+            Some(latency) => (latency * 7 + elapsed) / 8,
+            None => elapsed,
+        });
+    }
+}
+
+struct Pending {
+    peer_id: PeerId,
+    sent: Instant,
+    deadline: Instant,
 }
 
 #[derive(Debug, Error)]
@@ -41,141 +93,331 @@ pub enum ClientError {
     InternalError(rpc_kernel::Error),
     #[error("libp2p stop working")]
     Libp2p,
+    #[error("timeout")]
+    Timeout,
+    #[error("peer {0} disconnected")]
+    PeerGone(PeerId),
+}
+
+impl ClientError {
+    /// The query may succeed if sent again, possibly to another peer.
+    fn is_retriable(&self) -> bool {
+        !matches!(self, ClientError::Libp2p)
+    }
 }
 
-impl<S> Client<S>
+impl<S, D> Client<S, D>
 where
     S: Unpin + Stream<Item = TSwarmEvent> + DerefMut<Target = TSwarm>,
+    D: Storage,
 {
-    pub fn new(swarm: S, db: Arc<Db>) -> Self {
+    const TIMEOUT: Duration = Duration::from_secs(60);
+    const ATTEMPTS: usize = 4;
+    const MAX_IN_FLIGHT_PER_PEER: usize = 16;
+    /// The staking, the next epoch and the recent snarked ledgers are queried in turn.
+    const SERVED_LEDGERS: usize = 4;
+
+    pub fn new(swarm: S, db: Arc<D>, ledger_depth: u8) -> Self {
         Client {
             swarm,
-            peer: None,
-            stream: None,
+            peers: BTreeMap::new(),
+            pending: BTreeMap::new(),
+            completed: BTreeMap::new(),
             id: 1,
             db,
+            new_blocks: vec![],
+            served_ledgers: BTreeMap::new(),
+            ledger_depth,
         }
     }
 
+    pub fn ledger_depth(&self) -> u8 {
+        self.ledger_depth
+    }
+
+    /// Blocks received from gossip while waiting for a response.
+    pub fn take_new_blocks(&mut self) -> Vec<(v2::StateHash, v2::MinaBlockBlockStableV2)> {
+        std::mem::take(&mut self.new_blocks)
+    }
+
+    /// Sends the query and waits for the response. If the peer does not respond in time,
+    /// disconnects or responds with error, the query is sent again to another connected peer.
     pub async fn rpc<M>(&mut self, query: M::Query) -> Result<M::Response, ClientError>
+    where
+        M: RpcMethod,
+        M::Query: Clone,
+    {
+        let mut exclude = BTreeSet::new();
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let id = self.send::<M>(query.clone(), &exclude).await?;
+            let peer_id = self.peer_of(id);
+            match self.wait::<M>(id).await {
+                Ok(response) => return Ok(response),
+                Err(err) if err.is_retriable() && attempt < Self::ATTEMPTS => {
+                    log::warn!("{} attempt {attempt} failed: {err}", M::NAME);
+                    exclude.extend(peer_id);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Sends the query to the best available peer, not in `exclude` if possible.
+    /// Waits until some peer is ready. Returns the id of the query.
+    pub async fn send<M>(
+        &mut self,
+        query: M::Query,
+        exclude: &BTreeSet<PeerId>,
+    ) -> Result<i64, ClientError>
     where
         M: RpcMethod,
     {
-        let mut query = Some(query);
-        if let (Some(peer_id), Some(stream_id)) = (self.peer, self.stream) {
-            if let Some(query) = query.take() {
-                self.swarm
-                    .behaviour_mut()
-                    .rpc
-                    .query::<M>(peer_id, stream_id, self.id, query)?;
-                self.id += 1;
+        let (peer_id, stream_id) = loop {
+            if let Some(v) = self.select_peer(exclude) {
+                break v;
             }
+            self.poll_event().await?;
+        };
+
+        let id = self.id;
+        self.id += 1;
+        self.swarm
+            .behaviour_mut()
+            .rpc
+            .query::<M>(peer_id, stream_id, id, query)?;
+        let now = Instant::now();
+        self.pending.insert(
+            id,
+            Pending {
+                peer_id,
+                sent: now,
+                deadline: now + Self::TIMEOUT,
+            },
+        );
+        if let Some(state) = self.peers.get_mut(&peer_id) {
+            state.in_flight += 1;
         }
 
+        Ok(id)
+    }
+
+    /// The peer the query is sent to, while the query is pending.
+    pub fn peer_of(&self, id: i64) -> Option<PeerId> {
+        self.pending.get(&id).map(|p| p.peer_id)
+    }
+
+    fn select_peer(&self, exclude: &BTreeSet<PeerId>) -> Option<(PeerId, StreamId)> {
+        let ready = || {
+            self.peers
+                .iter()
+                .filter(|(_, state)| state.in_flight < Self::MAX_IN_FLIGHT_PER_PEER)
+                .filter_map(|(peer_id, state)| Some((*peer_id, state.stream?, state.cost())))
+        };
+        let by_cost =
+            |a: &(PeerId, StreamId, f64), b: &(PeerId, StreamId, f64)| a.2.total_cmp(&b.2);
+
+        ready()
+            .filter(|(peer_id, _, _)| !exclude.contains(peer_id))
+            .min_by(by_cost)
+            .or_else(|| ready().min_by(by_cost))
+            .map(|(peer_id, stream_id, _)| (peer_id, stream_id))
+    }
+
+    /// Waits for the response to the query with the given id.
+    pub async fn wait<M>(&mut self, id: i64) -> Result<M::Response, ClientError>
+    where
+        M: RpcMethod,
+    {
         loop {
-            match self.swarm.next().await.ok_or(ClientError::Libp2p)? {
-                SwarmEvent::Behaviour(BEvent::Rpc((peer_id, Event::ConnectionEstablished))) => {
-                    log::info!("new connection {peer_id}");
+            if let Some((_, result)) = self.completed.remove(&id) {
+                return Self::decode::<M>(result?);
+            }
+            self.poll_event().await?;
+        }
+    }
+
+    /// Waits for the response to any of the queries `ids` of the method `M`,
+    /// the responses to other queries are left to their callers.
+    pub async fn next_response<M, I>(
+        &mut self,
+        ids: I,
+    ) -> Result<(i64, Result<M::Response, ClientError>), ClientError>
+    where
+        M: RpcMethod,
+        I: IntoIterator<Item = i64>,
+    {
+        let ids = ids.into_iter().collect::<BTreeSet<_>>();
+        loop {
+            if let Some(id) = self.completed.keys().find(|id| ids.contains(id)).copied() {
+                let (_, result) = self.completed.remove(&id).expect("just found");
+                return Ok((id, result.and_then(Self::decode::<M>)));
+            }
+            self.poll_event().await?;
+        }
+    }
+
+    fn decode<M>(bytes: Vec<u8>) -> Result<M::Response, ClientError>
+    where
+        M: RpcMethod,
+    {
+        let mut bytes = bytes.as_slice();
+        let response = ResponsePayload::<M::Response>::binprot_read(&mut bytes)?
+            .0
+            .map_err(ClientError::InternalError)?
+            .0;
+        Ok(response)
+    }
+
+    fn complete(&mut self, id: i64, result: Result<Vec<u8>, ClientError>) {
+        let Some(pending) = self.pending.remove(&id) else {
+            return;
+        };
+        if let Some(state) = self.peers.get_mut(&pending.peer_id) {
+            state.in_flight -= 1;
+            match &result {
+                Ok(_) => state.success(pending.sent.elapsed()),
+                Err(_) => state.failures += 1,
+            }
+        }
+        let expires = Instant::now() + Self::TIMEOUT;
+        self.completed.insert(id, (expires, result));
+    }
 
-                    self.peer = Some(peer_id);
-                    self.swarm.behaviour_mut().rpc.open(peer_id, 0);
+    /// Drives the swarm until the next event or the nearest deadline of a query.
+    async fn poll_event(&mut self) -> Result<(), ClientError> {
+        let deadline = self
+            .pending
+            .values()
+            .map(|p| p.deadline)
+            .chain(self.completed.values().map(|(expires, _)| *expires))
+            .min();
+        let timeout = async move {
+            match deadline {
+                Some(deadline) => time::sleep_until(deadline).await,
+                None => future::pending::<()>().await,
+            }
+        };
+
+        tokio::select! {
+            event = self.swarm.next() => {
+                let event = event.ok_or(ClientError::Libp2p)?;
+                if let Some(block) = self.process(event) {
+                    self.new_blocks.push(block);
                 }
-                SwarmEvent::Behaviour(BEvent::Rpc((peer_id, Event::ConnectionClosed))) => {
-                    log::info!("connection closed {peer_id}");
-                    if self.peer == Some(peer_id) {
-                        self.peer = None;
-                        // TODO: resend
-                    }
+            }
+            () = timeout => {
+                let now = Instant::now();
+                let expired = self
+                    .pending
+                    .iter()
+                    .filter(|(_, p)| p.deadline <= now)
+                    .map(|(id, _)| *id)
+                    .collect::<Vec<_>>();
+                for id in expired {
+                    self.complete(id, Err(ClientError::Timeout));
                 }
-                SwarmEvent::Behaviour(BEvent::Rpc((
-                    peer_id,
-                    Event::Stream {
-                        stream_id,
-                        received,
-                    },
-                ))) => match received {
-                    Received::HandshakeDone => {
-                        log::info!("new stream {peer_id} {stream_id:?}");
-                        if self.stream.is_none() {
-                            self.stream = Some(stream_id);
-                        }
-
-                        if let (Some(peer_id), Some(stream_id)) = (self.peer, self.stream) {
-                            if let Some(query) = query.take() {
-                                self.swarm
-                                    .behaviour_mut()
-                                    .rpc
-                                    .query::<M>(peer_id, stream_id, self.id, query)?;
-                                self.id += 1;
-                            }
-                        }
+                // nobody waits for these responses
+                self.completed.retain(|id, (expires, _)| {
+                    let keep = *expires > now;
+                    if !keep {
+                        log::debug!("drop the response {id}, nobody takes it");
                     }
-                    Received::Menu(menu) => {
-                        log::info!("menu: {menu:?}");
-                    }
-                    Received::Query {
-                        header: QueryHeader { tag, version, id },
-                        bytes,
-                    } => self.handle_incoming(
-                        peer_id,
-                        stream_id,
-                        id,
-                        &tag.to_string_lossy(),
-                        version,
-                        bytes,
-                    ),
-                    Received::Response {
-                        header: ResponseHeader { id },
-                        bytes,
-                    } => {
-                        if id + 1 == self.id {
-                            let mut bytes = bytes.as_slice();
-                            let response =
-                                ResponsePayload::<M::Response>::binprot_read(&mut bytes)?
-                                    .0
-                                    .map_err(ClientError::InternalError)?
-                                    .0;
-                            return Ok(response);
-                        }
-                    }
-                },
-                event => {
-                    self.process(event);
-                }
+                    keep
+                });
             }
         }
+
+        Ok(())
     }
 
-    pub fn process(&mut self, event: TSwarmEvent) {
-        if let SwarmEvent::Behaviour(BEvent::Gossip(libp2p::gossipsub::Event::Message {
-            propagation_source,
-            message: Message { source, data, .. },
-            ..
-        })) = &event
-        {
-            self.peer = Some(propagation_source.clone());
-            self.stream = Some(StreamId::Outgoing(1));
-
-            if data.len() > 8 && data[8] == 0 {
-                let source = source
-                    .as_ref()
-                    .map(ToString::to_string)
-                    .map(Cow::Owned)
-                    .unwrap_or_else(|| "unknown".into());
-                let mut slice = &data[9..];
-                let block = match v2::MinaBlockBlockStableV2::binprot_read(&mut slice) {
-                    Ok(v) => v,
-                    Err(err) => {
-                        log::warn!("recv bad block: {err}");
-                        return;
+    /// Handles the event, returns the block received from gossip if any.
+    /// The block is not validated nor stored yet.
+    pub fn process(
+        &mut self,
+        event: TSwarmEvent,
+    ) -> Option<(v2::StateHash, v2::MinaBlockBlockStableV2)> {
+        match event {
+            SwarmEvent::Behaviour(BEvent::Rpc((peer_id, Event::ConnectionEstablished))) => {
+                log::info!("new connection {peer_id}");
+                // another connection to the peer may have queries in flight
+                self.peers.entry(peer_id).or_default();
+                self.swarm.behaviour_mut().rpc.open(peer_id, 0);
+            }
+            SwarmEvent::Behaviour(BEvent::Rpc((peer_id, Event::ConnectionClosed))) => {
+                log::info!("connection closed {peer_id}");
+                self.peers.remove(&peer_id);
+                let lost = self
+                    .pending
+                    .iter()
+                    .filter(|(_, p)| p.peer_id == peer_id)
+                    .map(|(id, _)| *id)
+                    .collect::<Vec<_>>();
+                for id in lost {
+                    self.complete(id, Err(ClientError::PeerGone(peer_id)));
+                }
+            }
+            SwarmEvent::Behaviour(BEvent::Rpc((
+                peer_id,
+                Event::Stream {
+                    stream_id,
+                    received,
+                },
+            ))) => match received {
+                Received::HandshakeDone => {
+                    log::info!("new stream {peer_id} {stream_id:?}");
+                    if let StreamId::Outgoing(_) = stream_id {
+                        self.peers.entry(peer_id).or_default().stream = Some(stream_id);
                     }
-                };
-                let height = block.height();
-                let hash = block.hash();
-                log::info!("block {height} {hash} from {source}");
-                self.db.put_block(hash, block).unwrap();
+                }
+                Received::Menu(menu) => {
+                    log::info!("menu: {menu:?}");
+                }
+                Received::Query {
+                    header: QueryHeader { tag, version, id },
+                    bytes,
+                } => self.handle_incoming(
+                    peer_id,
+                    stream_id,
+                    id,
+                    &tag.to_string_lossy(),
+                    version,
+                    bytes,
+                ),
+                Received::Response {
+                    header: ResponseHeader { id },
+                    bytes,
+                } => self.complete(id, Ok(bytes)),
+            },
+            SwarmEvent::Behaviour(BEvent::Gossip(libp2p::gossipsub::Event::Message {
+                message: Message { source, data, .. },
+                ..
+            })) => {
+                if data.len() > 8 && data[8] == 0 {
+                    let source = source
+                        .as_ref()
+                        .map(ToString::to_string)
+                        .map(Cow::Owned)
+                        .unwrap_or_else(|| "unknown".into());
+                    let mut slice = &data[9..];
+                    let block = match v2::MinaBlockBlockStableV2::binprot_read(&mut slice) {
+                        Ok(v) => v,
+                        Err(err) => {
+                            log::warn!("recv bad block: {err}");
+                            return None;
+                        }
+                    };
+                    let height = block.height();
+                    let hash = block.hash();
+                    log::info!("block {height} {hash} from {source}");
+                    return Some((hash, block));
+                }
             }
+            _ => {}
         }
+
+        None
     }
 
     pub fn handle_incoming(
@@ -190,33 +432,177 @@ where
         let mut slice = bytes.as_slice();
         match (tag, version) {
             (rpc::GetBestTipV2::NAME, rpc::GetBestTipV2::VERSION) => {
-                let _ = bytes;
-                self.swarm
-                    .behaviour_mut()
-                    .rpc
-                    .respond::<rpc::GetBestTipV2>(peer_id, stream_id, id, Ok(None))
-                    .unwrap();
+                let response = match self.db.best_tip() {
+                    Ok(Some(hash)) => self.proof_carrying(&hash),
+                    Ok(None) => Ok(None),
+                    Err(err) => Err(err),
+                };
+                let response = response.unwrap_or_else(|err| {
+                    log::warn!("cannot serve best tip: {err}");
+                    None
+                });
+                self.respond::<rpc::GetBestTipV2>(peer_id, stream_id, id, response);
+            }
+            (rpc::GetAncestryV2::NAME, rpc::GetAncestryV2::VERSION) => {
+                let Some((_, hash)) = Self::decode_query::<(
+                    v2::ConsensusProofOfStakeDataConsensusStateValueStableV2,
+                    v2::StateHash,
+                )>(tag, &mut slice) else {
+                    return;
+                };
+                let response = self.proof_carrying(&hash).unwrap_or_else(|err| {
+                    log::warn!("cannot serve ancestry of {hash}: {err}");
+                    None
+                });
+                self.respond::<rpc::GetAncestryV2>(peer_id, stream_id, id, response);
+            }
+            (rpc::GetTransitionChainV2::NAME, rpc::GetTransitionChainV2::VERSION) => {
+                let Some(hashes) = Self::decode_query::<Vec<v2::StateHash>>(tag, &mut slice) else {
+                    return;
+                };
+                // `None` unless every block is known
+                let response = hashes
+                    .iter()
+                    .map(|hash| self.db.block_full(hash).ok())
+                    .collect::<Option<Vec<_>>>();
+                self.respond::<rpc::GetTransitionChainV2>(peer_id, stream_id, id, response);
+            }
+            (
+                rpc::GetTransitionChainProofV1ForV2::NAME,
+                rpc::GetTransitionChainProofV1ForV2::VERSION,
+            ) => {
+                let Some(hash) = Self::decode_query::<v2::StateHash>(tag, &mut slice) else {
+                    return;
+                };
+                let response = self
+                    .db
+                    .chain_to(&hash, db::FRONTIER_LENGTH)
+                    .map(|chain| {
+                        let init = chain.first()?.hash();
+                        let list = chain
+                            .iter()
+                            .skip(1)
+                            .map(|b| b.header.protocol_state.body.hash());
+                        Some((init, list.collect()))
+                    })
+                    .unwrap_or_else(|err| {
+                        log::warn!("cannot serve transition chain proof of {hash}: {err}");
+                        None
+                    });
+                self.respond::<rpc::GetTransitionChainProofV1ForV2>(
+                    peer_id, stream_id, id, response,
+                );
             }
             (
                 rpc::GetStagedLedgerAuxAndPendingCoinbasesAtHashV2::NAME,
                 rpc::GetStagedLedgerAuxAndPendingCoinbasesAtHashV2::VERSION,
             ) => {
-                let hash = BinProtRead::binprot_read(&mut slice).unwrap();
-                let aux = self.db.aux(&hash).unwrap();
-                self.swarm
-                    .behaviour_mut()
-                    .rpc
-                    .respond::<rpc::GetStagedLedgerAuxAndPendingCoinbasesAtHashV2>(
-                        peer_id,
-                        stream_id,
-                        id,
-                        Ok(aux),
-                    )
-                    .unwrap();
+                let Some(hash) = Self::decode_query::<v2::StateHash>(tag, &mut slice) else {
+                    return;
+                };
+                let aux = self.db.aux(&hash).unwrap_or_else(|err| {
+                    log::warn!("cannot serve staged ledger aux: {err}");
+                    None
+                });
+                self.respond::<rpc::GetStagedLedgerAuxAndPendingCoinbasesAtHashV2>(
+                    peer_id, stream_id, id, aux,
+                );
+            }
+            (rpc::AnswerSyncLedgerQueryV2::NAME, rpc::AnswerSyncLedgerQueryV2::VERSION) => {
+                let Some((hash, query)) = Self::decode_query::<(
+                    v2::LedgerHash,
+                    v2::MinaLedgerSyncLedgerQueryStableV1,
+                )>(tag, &mut slice) else {
+                    return;
+                };
+                let answer = match self.served_ledger(&hash) {
+                    Ok(ledger) => Ok(ledger.serve_query(query)),
+                    Err(err) => {
+                        log::warn!("cannot serve ledger {hash}: {err}");
+                        Err(Info::CouldNotConstruct(err.to_string().into_bytes().into()))
+                    }
+                };
+                self.respond::<rpc::AnswerSyncLedgerQueryV2>(
+                    peer_id,
+                    stream_id,
+                    id,
+                    RpcResult(answer),
+                );
             }
             (tag, version) => {
                 log::warn!("unhandled query: {tag} {version}");
             }
         }
     }
+
+    fn decode_query<T>(tag: &str, slice: &mut &[u8]) -> Option<T>
+    where
+        T: BinProtRead,
+    {
+        T::binprot_read(slice)
+            .map_err(|err| log::warn!("bad query {tag}: {err}"))
+            .ok()
+    }
+
+    fn respond<M>(&mut self, peer_id: PeerId, stream_id: StreamId, id: i64, response: M::Response)
+    where
+        M: RpcMethod,
+    {
+        let result =
+            self.swarm
+                .behaviour_mut()
+                .rpc
+                .respond::<M>(peer_id, stream_id, id, Ok(response));
+        if let Err(err) = result {
+            log::warn!("cannot respond {} to {peer_id}: {err}", M::NAME);
+        }
+    }
+
+    /// The block with the proof of its ancestry, the merkle list of body hashes
+    /// from the ancestor `k` blocks back, or the oldest known one.
+    fn proof_carrying(
+        &self,
+        hash: &v2::StateHash,
+    ) -> Result<<rpc::GetBestTipV2 as RpcMethod>::Response, DbError> {
+        let chain = self.db.chain_to(hash, db::FRONTIER_LENGTH)?;
+        let Some(data) = chain.last().cloned() else {
+            return Ok(None);
+        };
+        let root = chain[0].clone();
+        let list = chain
+            .iter()
+            .skip(1)
+            .map(|b| b.header.protocol_state.body.hash())
+            .collect();
+
+        Ok(Some(v2::ProofCarryingDataStableV1 {
+            data,
+            proof: (list, root),
+        }))
+    }
+
+    /// The ledger to answer sync queries, built once per hash and kept in memory
+    /// while it is among the `SERVED_LEDGERS` most recently queried.
+    fn served_ledger(&mut self, hash: &v2::LedgerHash) -> Result<&mut SnarkedLedger, DbError> {
+        if !self.served_ledgers.contains_key(hash) {
+            let accounts = self.db.snarked_ledger(hash)?;
+            let ledger = SnarkedLedger::for_serving(self.ledger_depth, &accounts);
+            if self.served_ledgers.len() >= Self::SERVED_LEDGERS {
+                let oldest = self
+                    .served_ledgers
+                    .iter()
+                    .min_by_key(|(_, (used, _))| *used)
+                    .map(|(hash, _)| hash.clone());
+                if let Some(oldest) = oldest {
+                    self.served_ledgers.remove(&oldest);
+                }
+            }
+            self.served_ledgers
+                .insert(hash.clone(), (Instant::now(), ledger));
+        }
+
+        let (used, ledger) = self.served_ledgers.get_mut(hash).expect("just inserted");
+        *used = Instant::now();
+        Ok(ledger)
+    }
 }
@@ -0,0 +1,129 @@
+use std::cmp::Ordering;
+
+use blake2::{Blake2b, Digest, digest::consts::U32};
+use mina_p2p_messages::v2;
+
+type ConsensusState = v2::ConsensusProofOfStakeDataConsensusStateValueStableV2;
+
+fn consensus_state(block: &v2::MinaBlockBlockStableV2) -> &ConsensusState {
+    &block.header.protocol_state.body.consensus_state
+}
+
+/// Both chains share the staking ledger, so they forked recently and
+/// the longer chain wins. Otherwise the chains are compared by density.
+fn is_short_range(a: &ConsensusState, b: &ConsensusState) -> bool {
+    let check = |newer: &ConsensusState, older: &ConsensusState| {
+        let newer_epoch = newer.epoch_count.as_u32();
+        let older_epoch = older.epoch_count.as_u32();
+        if newer_epoch == older_epoch {
+            newer.staking_epoch_data.lock_checkpoint == older.staking_epoch_data.lock_checkpoint
+        } else if newer_epoch == older_epoch + 1 {
+            newer.staking_epoch_data.lock_checkpoint == older.next_epoch_data.lock_checkpoint
+        } else {
+            false
+        }
+    };
+
+    if a.epoch_count.as_u32() >= b.epoch_count.as_u32() {
+        check(a, b)
+    } else {
+        check(b, a)
+    }
+}
+
+fn vrf_hash(state: &ConsensusState) -> Vec<u8> {
+    Blake2b::<U32>::digest(state.last_vrf_output.0.as_ref()).to_vec()
+}
+
+fn compare_hashes(a: &v2::StateHash, b: &v2::StateHash) -> Ordering {
+    a.to_fp().ok().cmp(&b.to_fp().ok())
+}
+
+/// Mina chain selection. Returns `true` if the `candidate` must replace the `existing` best tip.
+/// The long range fork is decided by `min_window_density` of the two states as is. Mina first
+/// projects the sub-window densities of each state to the slot of the newer one and takes
+/// the minimum of the projected and the recorded density, that step is skipped here.
+/// It matters only when the chains are far apart in slots, then the older chain may win here
+/// with the density it had before the empty slots that followed it.
+pub fn select(
+    existing: &v2::MinaBlockBlockStableV2,
+    existing_hash: &v2::StateHash,
+    candidate: &v2::MinaBlockBlockStableV2,
+    candidate_hash: &v2::StateHash,
+) -> bool {
+    let e = consensus_state(existing);
+    let c = consensus_state(candidate);
+
+    let density = if is_short_range(e, c) {
+        Ordering::Equal
+    } else {
+        c.min_window_density
+            .as_u32()
+            .cmp(&e.min_window_density.as_u32())
+    };
+
+    density
+        .then_with(|| {
+            c.blockchain_length
+                .as_u32()
+                .cmp(&e.blockchain_length.as_u32())
+        })
+        .then_with(|| vrf_hash(c).cmp(&vrf_hash(e)))
+        .then_with(|| compare_hashes(candidate_hash, existing_hash))
+        .is_gt()
+}
+
+#[cfg(test)]
+mod tests {
+    use mina_p2p_messages::v2;
+
+    use super::{select, vrf_hash};
+    use crate::testing::{bytes, empty, number};
+
+    fn block(
+        epoch: u8,
+        density: u8,
+        length: u8,
+        vrf: u8,
+    ) -> (v2::StateHash, v2::MinaBlockBlockStableV2) {
+        let mut block = empty::<v2::MinaBlockBlockStableV2>();
+        let state = &mut block.header.protocol_state.body.consensus_state;
+        state.epoch_count = number(epoch);
+        state.min_window_density = number(density);
+        state.blockchain_length = number(length);
+        state.last_vrf_output = bytes(vrf);
+
+        (block.hash(), block)
+    }
+
+    #[test]
+    fn short_range_prefers_longer() {
+        // the same epoch and the same lock checkpoint
+        let (e_hash, existing) = block(1, 10, 5, 0);
+        let (c_hash, candidate) = block(1, 1, 6, 0);
+
+        assert!(select(&existing, &e_hash, &candidate, &c_hash));
+        assert!(!select(&candidate, &c_hash, &existing, &e_hash));
+    }
+
+    #[test]
+    fn long_range_prefers_denser() {
+        // the epochs are two apart, so the chains do not share the staking ledger
+        let (e_hash, existing) = block(1, 5, 10, 0);
+        let (c_hash, candidate) = block(3, 6, 8, 0);
+
+        assert!(select(&existing, &e_hash, &candidate, &c_hash));
+        assert!(!select(&candidate, &c_hash, &existing, &e_hash));
+    }
+
+    #[test]
+    fn vrf_breaks_tie() {
+        let (a_hash, a) = block(1, 5, 5, 1);
+        let (b_hash, b) = block(1, 5, 5, 2);
+
+        let b_wins = vrf_hash(&b.header.protocol_state.body.consensus_state)
+            > vrf_hash(&a.header.protocol_state.body.consensus_state);
+        assert_eq!(select(&a, &a_hash, &b, &b_hash), b_wins);
+        assert_eq!(select(&b, &b_hash, &a, &a_hash), !b_wins);
+    }
+}
@@ -1,20 +1,30 @@
-use std::sync::Mutex;
-use std::{path::Path, time::Duration};
+mod migration;
+mod fsck;
+mod storage;
+#[cfg(test)]
+mod memory;
 
-use rocksdb::{DBWithThreadMode, SingleThreaded, ColumnFamilyDescriptor};
+use std::{ops::Range, path::Path, time::Duration, sync::Mutex};
+
+use rocksdb::{DBWithThreadMode, SingleThreaded, ColumnFamilyDescriptor, MergeOperands, WriteBatch};
 use thiserror::Error;
 use mina_p2p_messages::binprot::{self, BinProtWrite, BinProtRead};
 use mina_p2p_messages::v2;
 use mina_p2p_messages::rpc::GetStagedLedgerAuxAndPendingCoinbasesAtHashV2Response as Aux;
 
+use crate::{
+    proof::{self, ProofStatus},
+    replay::ReplayResult,
+};
+
+pub use self::migration::{MigrationReport, SCHEMA_VERSION};
+pub use self::fsck::FsckReport;
+pub use self::storage::Storage;
+
 pub struct Db {
     inner: DBWithThreadMode<SingleThreaded>,
-    cache: Mutex<DbCache>,
-}
-
-#[derive(Default)]
-struct DbCache {
-    hashes_at_height: Option<(u32, Vec<v2::StateHash>)>,
+    // serializes updates of the canonical chain
+    canonical_lock: Mutex<()>,
 }
 
 #[derive(Debug, Error)]
@@ -23,6 +33,8 @@ pub enum DbError {
     Inner(#[from] rocksdb::Error),
     #[error("db binprot {_0}")]
     Binprot(#[from] binprot::Error),
+    #[error("db json {_0}")]
+    Json(#[from] serde_json::Error),
     #[error("bad index")]
     BadIndex,
     #[error("ledger not found {_0}")]
@@ -31,13 +43,34 @@ pub enum DbError {
     BlockNotFound(v2::StateHash),
     #[error("staged ledger aux info not found {_0}")]
     AuxNotFound(v2::StateHash),
+    #[error("ledger diff not found {_0}")]
+    LedgerDiffNotFound(v2::StateHash),
     #[error("root not found")]
     RootNotFound,
+    #[error("schema version {_0} is newer than supported {}", SCHEMA_VERSION)]
+    UnsupportedSchema(u32),
+}
+
+/// Accounts of the snarked ledger that changed in a block, with their positions.
+pub type LedgerDiff = Vec<(u64, v2::MinaBaseAccountBinableArgStableV2)>;
+
+/// Compute the diff that turns the `old` snarked ledger into the `new` one.
+pub fn diff_ledgers(
+    old: &[v2::MinaBaseAccountBinableArgStableV2],
+    new: &[v2::MinaBaseAccountBinableArgStableV2],
+) -> LedgerDiff {
+    new.iter()
+        .enumerate()
+        .filter(|(pos, account)| old.get(*pos) != Some(*account))
+        .map(|(pos, account)| (pos as u64, account.clone()))
+        .collect()
 }
 
 pub enum BlockId {
     Latest,
     Forward(u32),
+    /// Blocks of the canonical chain starting from the height, one per height.
+    Canonical(u32),
 }
 
 pub trait BlockHeader {
@@ -69,10 +102,87 @@ impl BlockHeader for v2::MinaBlockBlockStableV2 {
     }
 }
 
+/// Number of blocks peers keep before the root of the transition frontier.
+pub const FRONTIER_LENGTH: u32 = 290;
+
+const SCHEMA_VERSION_KEY: &[u8] = b"schema_version";
+const BEST_TIP_KEY: &[u8] = b"best_tip";
+
+/// Column families of the database.
+const COLUMN_FAMILIES: [&str; 12] = [
+    // v2::LedgerHash -> Vec<v2::MinaBaseAccountBinableArgStableV2>
+    "ledger",
+    // v2::StateHash -> mina_p2p_messages::rpc::GetStagedLedgerAuxAndPendingCoinbasesAtHashV2Response
+    "aux",
+    // v2::StateHash -> v2::MinaBlockBlockStableV2
+    "block",
+    // u32 -> Vec<v2::StateHash>
+    "block_hash_by_height",
+    // v2::StateHash -> LedgerDiff
+    "ledger_diff",
+    // &[u8] -> metadata, e.g. `schema_version` -> u32, `best_tip` -> v2::StateHash
+    "meta",
+    // v2::StateHash -> Vec<v2::StateHash>, parent to children
+    "children",
+    // u32 -> v2::StateHash, the block of the canonical chain at the height
+    "canonical",
+    // (v2::LedgerHash, u8, u32) -> v2::MinaLedgerSyncLedgerAnswerStableV2, answers received
+    // by the unfinished sync of the ledger, the key is the ledger, the depth and the position
+    // of the subtree
+    "ledger_sync",
+    // v2::StateHash -> (String, v2::MinaBlockBlockStableV2), blocks that failed validation
+    // with the reason
+    "quarantine",
+    // v2::StateHash -> u8, the status of the protocol state proof, see `ProofStatus`
+    "proof_status",
+    // v2::StateHash -> ReplayResult as json, the result of applying the block
+    // to the staged ledger of its parent
+    "replay",
+];
+
+/// Column families with `Vec<v2::StateHash>` values, merged by `merge_hashes`.
+const MERGED_COLUMN_FAMILIES: [&str; 2] = ["block_hash_by_height", "children"];
+
+/// Merge operator for `Vec<v2::StateHash>` values, the union of all lists preserving order.
+/// Operands are lists as well, so the operator is associative.
+fn merge_hashes(key: &[u8], existing: Option<&[u8]>, operands: &MergeOperands) -> Option<Vec<u8>> {
+    let mut hashes = Vec::<v2::StateHash>::new();
+    for mut bytes in existing.into_iter().chain(operands) {
+        match Vec::<v2::StateHash>::binprot_read(&mut bytes) {
+            Ok(list) => {
+                for hash in list {
+                    if !hashes.contains(&hash) {
+                        hashes.push(hash);
+                    }
+                }
+            }
+            Err(err) => log::error!("bad hash list at {key:?}: {err}"),
+        }
+    }
+
+    let mut value = vec![];
+    hashes.binprot_write(&mut value).ok()?;
+    Some(value)
+}
+
 impl Db {
     const TTL: Duration = Duration::from_secs(0);
 
+    /// Opens the database and brings it to the current schema version.
     pub fn open<P>(path: P) -> Result<Db, DbError>
+    where
+        P: AsRef<Path>,
+    {
+        let db = Self::open_unmigrated(path)?;
+        for report in db.migrate(false)? {
+            log::info!("{report}");
+        }
+
+        Ok(db)
+    }
+
+    /// Opens the database without running migrations.
+    pub fn open_unmigrated<P>(path: P) -> Result<Db, DbError>
     where
         P: AsRef<Path>,
     {
@@ -80,31 +190,247 @@ impl Db {
         opts.create_if_missing(true);
         opts.create_missing_column_families(true);
 
-        let cfs = [
-            // v2::LedgerHash -> Vec<v2::MinaBaseAccountBinableArgStableV2>
-            ColumnFamilyDescriptor::new("ledger", Default::default()),
-            // v2::StateHash -> mina_p2p_messages::rpc::GetStagedLedgerAuxAndPendingCoinbasesAtHashV2Response
-            ColumnFamilyDescriptor::new("aux", Default::default()),
-            // v2::StateHash -> v2::MinaBlockBlockStableV2
-            ColumnFamilyDescriptor::new("block", Default::default()),
-            // u32 -> Vec<v2::StateHash>
-            ColumnFamilyDescriptor::new("block_hash_by_height", Default::default()),
-        ];
+        let cfs = COLUMN_FAMILIES.iter().map(|name| {
+            let mut opts = rocksdb::Options::default();
+            if MERGED_COLUMN_FAMILIES.contains(name) {
+                opts.set_merge_operator_associative("merge_hashes", merge_hashes);
+            }
+            ColumnFamilyDescriptor::new(*name, opts)
+        });
 
         let inner = rocksdb::DB::open_cf_descriptors_with_ttl(&opts, path, cfs, Self::TTL)?;
 
         Ok(Db {
             inner,
-            cache: Mutex::new(DbCache::default()),
+            canonical_lock: Mutex::new(()),
         })
     }
 
-    pub fn root(&self) -> Result<u32, DbError> {
-        let cf = self.inner.cf_handle("ledger").expect("must exist");
+    /// Opens the existing database read-only with the column families it has,
+    /// so the migration dry run does not create anything.
+    pub fn open_read_only<P>(path: P) -> Result<Db, DbError>
+    where
+        P: AsRef<Path>,
+    {
+        // the options apply to every column family, the operator is only used by the merged ones
+        let mut opts = rocksdb::Options::default();
+        opts.set_merge_operator_associative("merge_hashes", merge_hashes);
+
+        // a database created before some migration lacks the column families it adds
+        let cfs = rocksdb::DB::list_cf(&opts, &path)?;
+        // the values keep the timestamp the ttl database appends, the readers ignore the suffix
+        let inner = rocksdb::DB::open_cf_for_read_only(&opts, path, cfs, false)?;
+
+        Ok(Db {
+            inner,
+            canonical_lock: Mutex::new(()),
+        })
+    }
 
+    /// Opens the database of the running primary instance read-only, as a RocksDB secondary
+    /// that keeps its own files in `secondary_path`. Sees the writes of the primary
+    /// made before opening, or before the last `catch_up`.
+    /// RocksDB has no ttl secondary, so the values keep the 4 byte timestamp
+    /// the ttl database appends. Binprot reads ignore the suffix, the json values
+    /// of `replay` are read as a stream for the same reason.
+    pub fn open_secondary<P, Q>(path: P, secondary_path: Q) -> Result<Db, DbError>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        // the options apply to every column family, the operator is only used by the merged ones
+        let mut opts = rocksdb::Options::default();
+        opts.set_merge_operator_associative("merge_hashes", merge_hashes);
+        // the secondary instance keeps every file open
+        opts.set_max_open_files(-1);
+
+        let inner =
+            rocksdb::DB::open_cf_as_secondary(&opts, path, secondary_path, COLUMN_FAMILIES)?;
+        let db = Db {
+            inner,
+            canonical_lock: Mutex::new(()),
+        };
+        let pending = db.migrate(true)?;
+        if !pending.is_empty() {
+            log::warn!("the primary has {} pending migrations", pending.len());
+        }
+
+        Ok(db)
+    }
+
+    /// Makes the writes of the primary visible to the secondary instance.
+    pub fn catch_up(&self) -> Result<(), DbError> {
+        self.inner.try_catch_up_with_primary().map_err(Into::into)
+    }
+
+    /// The stored schema version. A database without the version is either
+    /// fresh, or created before versioning was introduced, which is version `0`.
+    pub fn schema_version(&self) -> Result<Option<u32>, DbError> {
+        // the database opened read-only may predate the column family
+        let Some(cf) = self.inner.cf_handle("meta") else {
+            return Ok(None);
+        };
+        let Some(value) = self.inner.get_cf(cf, SCHEMA_VERSION_KEY)? else {
+            return Ok(None);
+        };
+        value
+            .get(..4)
+            .and_then(|v| v.try_into().ok())
+            .map(u32::from_be_bytes)
+            .map(Some)
+            .ok_or(DbError::BadIndex)
+    }
+
+    /// Creates a consistent copy of the database in the directory, which must not exist.
+    /// Files are hard linked if the directory is on the same filesystem.
+    pub fn checkpoint<P>(&self, path: P) -> Result<(), DbError>
+    where
+        P: AsRef<Path>,
+    {
+        rocksdb::checkpoint::Checkpoint::new(&self.inner)?
+            .create_checkpoint(path)
+            .map_err(Into::into)
+    }
+
+    fn put_schema_version(&self, version: u32) -> Result<(), DbError> {
+        let cf = self.inner.cf_handle("meta").expect("must exist");
         self.inner
+            .put_cf(cf, SCHEMA_VERSION_KEY, version.to_be_bytes())
+            .map_err(Into::into)
+    }
+
+    fn is_empty(&self) -> bool {
+        let cf = self.inner.cf_handle("block").expect("must exist");
+        let no_blocks = self
+            .inner
             .iterator_cf(cf, rocksdb::IteratorMode::Start)
             .next()
+            .is_none();
+        no_blocks && self.root().is_err()
+    }
+
+    /// Runs every pending migration step. If `dry_run` is set,
+    /// only reports what would change and leaves the database untouched.
+    pub fn migrate(&self, dry_run: bool) -> Result<Vec<MigrationReport>, DbError> {
+        let version = match self.schema_version()? {
+            Some(version) => version,
+            None if self.is_empty() => {
+                if !dry_run {
+                    self.put_schema_version(SCHEMA_VERSION)?;
+                }
+                return Ok(vec![]);
+            }
+            None => 0,
+        };
+        if version > SCHEMA_VERSION {
+            return Err(DbError::UnsupportedSchema(version));
+        }
+
+        let mut reports = vec![];
+        for migration in &migration::MIGRATIONS[version as usize..] {
+            let affected = (migration.run)(self, dry_run)?;
+            if !dry_run {
+                self.put_schema_version(migration.version)?;
+            }
+            reports.push(MigrationReport {
+                version: migration.version,
+                description: migration.description,
+                affected,
+                dry_run,
+            });
+        }
+
+        Ok(reports)
+    }
+
+    #[allow(dead_code)]
+    pub fn remove_block(&self, height: u32) {
+        let cf = self
+            .inner
+            .cf_handle("block_hash_by_height")
+            .expect("must exist");
+        if let Ok(Some(v)) = self.inner.get_cf(cf, height.to_be_bytes()) {
+            let mut batch = WriteBatch::default();
+            let block_cf = self.inner.cf_handle("block").expect("must exist");
+            let mut s = v.as_slice();
+            for hash in Vec::<v2::StateHash>::binprot_read(&mut s).unwrap() {
+                let mut key = vec![];
+                hash.binprot_write(&mut key).unwrap();
+                batch.delete_cf(block_cf, &key);
+            }
+            batch.delete_cf(cf, height.to_be_bytes());
+            self.inner.write(batch).unwrap();
+        }
+    }
+
+    pub fn proof_status(&self, hash: &v2::StateHash) -> Result<Option<ProofStatus>, DbError> {
+        let mut key = vec![];
+        hash.binprot_write(&mut key).unwrap();
+
+        let cf = self.inner.cf_handle("proof_status").expect("must exist");
+        let Some(value) = self.inner.get_pinned_cf(cf, key)? else {
+            return Ok(None);
+        };
+        value
+            .first()
+            .copied()
+            .and_then(ProofStatus::from_byte)
+            .map(Some)
+            .ok_or(DbError::BadIndex)
+    }
+
+    pub fn put_proof_status(
+        &self,
+        hash: &v2::StateHash,
+        status: ProofStatus,
+    ) -> Result<(), DbError> {
+        let mut key = vec![];
+        hash.binprot_write(&mut key).unwrap();
+
+        let cf = self.inner.cf_handle("proof_status").expect("must exist");
+        self.inner
+            .put_cf(cf, key, [status.to_byte()])
+            .map_err(Into::into)
+    }
+
+    /// Verifies the protocol state proofs of the stored blocks and stores the status of each.
+    /// Blocks that already have the status are skipped unless `redo` is set.
+    /// Returns the number of valid and invalid proofs.
+    pub fn verify_proofs(&self, redo: bool) -> Result<(usize, usize), DbError> {
+        let cf = self.inner.cf_handle("block").expect("must exist");
+
+        let (mut valid, mut invalid) = (0, 0);
+        for item in self.inner.iterator_cf(cf, rocksdb::IteratorMode::Start) {
+            let (key, value) = item?;
+            let hash = v2::StateHash::binprot_read(&mut key.as_ref())?;
+            if !redo && self.proof_status(&hash)?.is_some() {
+                continue;
+            }
+            let block = v2::MinaBlockBlockStableV2::binprot_read(&mut value.as_ref())?;
+
+            let status = proof::verify(&block);
+            match status {
+                ProofStatus::Valid => valid += 1,
+                ProofStatus::Invalid => {
+                    log::warn!("invalid proof {} {hash}", block.height());
+                    invalid += 1;
+                }
+            }
+            self.put_proof_status(&hash, status)?;
+        }
+
+        Ok((valid, invalid))
+    }
+}
+
+impl Storage for Db {
+    fn root(&self) -> Result<u32, DbError> {
+        let cf = self.inner.cf_handle("ledger").expect("must exist");
+
+        // the ledgers are stored in the same column family under the longer keys
+        self.inner
+            .iterator_cf(cf, rocksdb::IteratorMode::Start)
+            .find(|r| r.as_ref().map_or(true, |(key, _)| key.len() == 4))
             .ok_or(DbError::RootNotFound)
             .and_then(|r| {
                 r.map_err(Into::into).and_then(|(key, _)| {
@@ -117,51 +443,110 @@ impl Db {
             })
     }
 
-    pub fn block(
+    fn block(
         &self,
         id: BlockId,
-    ) -> impl Iterator<Item = Result<(u32, Vec<v2::StateHash>), DbError>> + '_ {
-        let cf_handle = self
-            .inner
-            .cf_handle("block_hash_by_height")
-            .expect("must exist");
+    ) -> Box<dyn Iterator<Item = Result<(u32, Vec<v2::StateHash>), DbError>> + '_> {
+        let canonical = matches!(id, BlockId::Canonical(_));
+        let cf_handle = if canonical {
+            self.inner.cf_handle("canonical").expect("must exist")
+        } else {
+            self.inner
+                .cf_handle("block_hash_by_height")
+                .expect("must exist")
+        };
         let pos_bytes;
         let mode = match id {
             BlockId::Latest => rocksdb::IteratorMode::End,
-            BlockId::Forward(pos) => {
+            BlockId::Forward(pos) | BlockId::Canonical(pos) => {
                 pos_bytes = pos.to_be_bytes();
                 rocksdb::IteratorMode::From(&pos_bytes, rocksdb::Direction::Forward)
             }
         };
-        self.inner.iterator_cf(cf_handle, mode).map(|x| {
+        let it = self.inner.iterator_cf(cf_handle, mode).map(move |x| {
             let (k, v) = x?;
             let mut v = v.as_ref();
             let height = u32::from_be_bytes(k.as_ref().try_into().map_err(|_| DbError::BadIndex)?);
-            let hash = Vec::<v2::StateHash>::binprot_read(&mut v)?;
+            let hash = if canonical {
+                vec![v2::StateHash::binprot_read(&mut v)?]
+            } else {
+                Vec::<v2::StateHash>::binprot_read(&mut v)?
+            };
 
             Ok((height, hash))
-        })
+        });
+
+        Box::new(it)
     }
 
-    #[allow(dead_code)]
-    pub fn remove_block(&self, height: u32) {
-        let cf = self
-            .inner
-            .cf_handle("block_hash_by_height")
-            .expect("must exist");
-        if let Ok(Some(v)) = self.inner.get_cf(cf, height.to_be_bytes()) {
-            let block_cf = self.inner.cf_handle("block").expect("must exist");
-            let mut s = v.as_slice();
-            for hash in Vec::<v2::StateHash>::binprot_read(&mut s).unwrap() {
-                let mut key = vec![];
-                hash.binprot_write(&mut key).unwrap();
-                self.inner.delete_cf(block_cf, &key).unwrap();
+    fn contains_block(&self, hash: &v2::StateHash) -> Result<bool, DbError> {
+        let mut key = vec![];
+        hash.binprot_write(&mut key).unwrap();
+        let cf = self.inner.cf_handle("block").expect("must exist");
+        Ok(self.inner.get_pinned_cf(cf, key)?.is_some())
+    }
+
+    fn missing_parents(&self) -> Result<Vec<(u32, v2::StateHash)>, DbError> {
+        let root = self.root()?;
+        let cf = self.inner.cf_handle("children").expect("must exist");
+        let mut missing = vec![];
+        for item in self.inner.iterator_cf(cf, rocksdb::IteratorMode::Start) {
+            let (key, value) = item?;
+            let mut slice = key.as_ref();
+            let parent = v2::StateHash::binprot_read(&mut slice)?;
+            if self.contains_block(&parent)? {
+                continue;
+            }
+            let mut slice = value.as_ref();
+            let children = Vec::<v2::StateHash>::binprot_read(&mut slice)?;
+            let Some(child) = children.first() else {
+                continue;
+            };
+            let height = self.block_full(child)?.height() - 1;
+            if height >= root {
+                missing.push((height, parent));
             }
-            self.inner.delete_cf(cf, height.to_be_bytes()).unwrap();
         }
+
+        Ok(missing)
+    }
+
+    fn children(&self, hash: &v2::StateHash) -> Result<Vec<v2::StateHash>, DbError> {
+        let mut key = vec![];
+        hash.binprot_write(&mut key).unwrap();
+        let cf = self.inner.cf_handle("children").expect("must exist");
+        let Some(value) = self.inner.get_cf(cf, key)? else {
+            return Ok(vec![]);
+        };
+        let mut slice = value.as_slice();
+        let children = BinProtRead::binprot_read(&mut slice)?;
+
+        Ok(children)
+    }
+
+    fn canonical(&self, height: u32) -> Result<Option<v2::StateHash>, DbError> {
+        let cf = self.inner.cf_handle("canonical").expect("must exist");
+        let Some(value) = self.inner.get_cf(cf, height.to_be_bytes())? else {
+            return Ok(None);
+        };
+        let mut slice = value.as_slice();
+        let hash = BinProtRead::binprot_read(&mut slice)?;
+
+        Ok(Some(hash))
+    }
+
+    fn best_tip(&self) -> Result<Option<v2::StateHash>, DbError> {
+        let cf = self.inner.cf_handle("meta").expect("must exist");
+        let Some(value) = self.inner.get_cf(cf, BEST_TIP_KEY)? else {
+            return Ok(None);
+        };
+        let mut slice = value.as_slice();
+        let hash = BinProtRead::binprot_read(&mut slice)?;
+
+        Ok(Some(hash))
     }
 
-    pub fn block_full(&self, hash: &v2::StateHash) -> Result<v2::MinaBlockBlockStableV2, DbError> {
+    fn block_full(&self, hash: &v2::StateHash) -> Result<v2::MinaBlockBlockStableV2, DbError> {
         let mut key = vec![];
         hash.binprot_write(&mut key).unwrap();
         let cf = self.inner.cf_handle("block").expect("must exist");
@@ -175,7 +560,7 @@ impl Db {
         Ok(block)
     }
 
-    pub fn ledger(
+    fn ledger(
         &self,
         hash: &v2::LedgerHash,
     ) -> Result<Vec<v2::MinaBaseAccountBinableArgStableV2>, DbError> {
@@ -192,7 +577,21 @@ impl Db {
         Ok(ledger)
     }
 
-    pub fn aux(&self, hash: &v2::StateHash) -> Result<Aux, DbError> {
+    fn ledger_diff(&self, hash: &v2::StateHash) -> Result<LedgerDiff, DbError> {
+        let mut key = vec![];
+        hash.binprot_write(&mut key).unwrap();
+        let cf = self.inner.cf_handle("ledger_diff").expect("must exist");
+        let value = self
+            .inner
+            .get_cf(cf, key)?
+            .ok_or_else(|| DbError::LedgerDiffNotFound(hash.clone()))?;
+        let mut slice = value.as_slice();
+        let diff = BinProtRead::binprot_read(&mut slice)?;
+
+        Ok(diff)
+    }
+
+    fn aux(&self, hash: &v2::StateHash) -> Result<Aux, DbError> {
         let mut key = vec![];
         hash.binprot_write(&mut key).unwrap();
         let cf = self.inner.cf_handle("aux").expect("must exist");
@@ -206,14 +605,14 @@ impl Db {
         Ok(aux)
     }
 
-    pub fn put_root(&self, height: u32) -> Result<(), DbError> {
+    fn put_root(&self, height: u32) -> Result<(), DbError> {
         let cf = self.inner.cf_handle("ledger").expect("must exist");
         self.inner.put_cf(cf, height.to_be_bytes(), [])?;
 
         Ok(())
     }
 
-    pub fn put_ledger(
+    fn put_ledger(
         &self,
         hash: v2::LedgerHash,
         ledger: Vec<v2::MinaBaseAccountBinableArgStableV2>,
@@ -227,7 +626,79 @@ impl Db {
         self.inner.put_cf(cf, key, value).map_err(Into::into)
     }
 
-    pub fn put_aux(&self, hash: v2::StateHash, aux: Aux) -> Result<(), DbError> {
+    fn ledger_sync_answers(
+        &self,
+        ledger: &v2::LedgerHash,
+    ) -> Box<
+        dyn Iterator<Item = Result<(i32, u32, v2::MinaLedgerSyncLedgerAnswerStableV2), DbError>>
+            + '_,
+    > {
+        let mut prefix = vec![];
+        ledger.binprot_write(&mut prefix).unwrap();
+
+        let cf = self.inner.cf_handle("ledger_sync").expect("must exist");
+        let it = self
+            .inner
+            .iterator_cf(
+                cf,
+                rocksdb::IteratorMode::From(&prefix, rocksdb::Direction::Forward),
+            )
+            .take_while(move |x| match x {
+                Ok((k, _)) => k.starts_with(&prefix),
+                Err(_) => true,
+            })
+            .map(|x| {
+                let (k, v) = x?;
+                let mut v = v.as_ref();
+                let (depth, pos) = k[k.len() - 5..].split_at(1);
+                let pos = u32::from_be_bytes(pos.try_into().map_err(|_| DbError::BadIndex)?);
+                let answer = BinProtRead::binprot_read(&mut v)?;
+
+                Ok((depth[0] as i32, pos, answer))
+            });
+
+        Box::new(it)
+    }
+
+    fn put_ledger_sync_answer(
+        &self,
+        ledger: &v2::LedgerHash,
+        depth: i32,
+        pos: u32,
+        answer: &v2::MinaLedgerSyncLedgerAnswerStableV2,
+    ) -> Result<(), DbError> {
+        let mut key = vec![];
+        ledger.binprot_write(&mut key).unwrap();
+        key.push(depth as u8);
+        key.extend_from_slice(&pos.to_be_bytes());
+        let mut value = vec![];
+        answer.binprot_write(&mut value).unwrap();
+
+        let cf = self.inner.cf_handle("ledger_sync").expect("must exist");
+        self.inner.put_cf(cf, key, value).map_err(Into::into)
+    }
+
+    fn clear_ledger_sync(&self) -> Result<(), DbError> {
+        let cf = self.inner.cf_handle("ledger_sync").expect("must exist");
+        for item in self.inner.iterator_cf(cf, rocksdb::IteratorMode::Start) {
+            let (key, _) = item?;
+            self.inner.delete_cf(cf, key)?;
+        }
+
+        Ok(())
+    }
+
+    fn put_ledger_diff(&self, hash: v2::StateHash, diff: LedgerDiff) -> Result<(), DbError> {
+        let mut key = vec![];
+        hash.binprot_write(&mut key).unwrap();
+        let mut value = vec![];
+        diff.binprot_write(&mut value).unwrap();
+
+        let cf = self.inner.cf_handle("ledger_diff").expect("must exist");
+        self.inner.put_cf(cf, key, value).map_err(Into::into)
+    }
+
+    fn put_aux(&self, hash: v2::StateHash, aux: Aux) -> Result<(), DbError> {
         let mut key = vec![];
         hash.binprot_write(&mut key).unwrap();
         let mut value = vec![];
@@ -237,49 +708,239 @@ impl Db {
         self.inner.put_cf(cf, key, value).map_err(Into::into)
     }
 
-    pub fn put_block(
+    fn put_replay_result(
+        &self,
+        hash: &v2::StateHash,
+        result: &ReplayResult,
+    ) -> Result<(), DbError> {
+        let mut key = vec![];
+        hash.binprot_write(&mut key).unwrap();
+        let value = serde_json::to_vec(result)?;
+
+        let cf = self.inner.cf_handle("replay").expect("must exist");
+        self.inner.put_cf(cf, key, value).map_err(Into::into)
+    }
+
+    fn replay_divergences(&self) -> Result<Vec<(v2::StateHash, ReplayResult)>, DbError> {
+        let cf = self.inner.cf_handle("replay").expect("must exist");
+
+        let mut divergences = vec![];
+        for item in self.inner.iterator_cf(cf, rocksdb::IteratorMode::Start) {
+            let (key, value) = item?;
+            // a secondary instance reads the timestamp the ttl database appends to the value
+            let result = serde_json::Deserializer::from_slice(&value)
+                .into_iter::<ReplayResult>()
+                .next()
+                .ok_or(DbError::BadIndex)??;
+            if result != ReplayResult::Ok {
+                let hash = v2::StateHash::binprot_read(&mut key.as_ref())?;
+                divergences.push((hash, result));
+            }
+        }
+
+        Ok(divergences)
+    }
+
+    fn put_quarantined(
+        &self,
+        hash: v2::StateHash,
+        reason: String,
+        block: v2::MinaBlockBlockStableV2,
+    ) -> Result<(), DbError> {
+        let mut key = vec![];
+        hash.binprot_write(&mut key).unwrap();
+        let mut value = vec![];
+        (reason, block).binprot_write(&mut value).unwrap();
+
+        let cf = self.inner.cf_handle("quarantine").expect("must exist");
+        self.inner.put_cf(cf, key, value).map_err(Into::into)
+    }
+
+    /// Stores the block and the indexes in a single write batch.
+    fn put_block(
         &self,
         hash: v2::StateHash,
         block: v2::MinaBlockBlockStableV2,
     ) -> Result<(), DbError> {
         let height = block.height();
-        let mut cache = self.cache.lock().expect("mutex");
-        let hashes = match &mut cache.hashes_at_height {
-            Some((h, hashes)) if *h == height => {
-                hashes.push(hash.clone());
-                hashes.clone()
-            }
-            _ => {
-                let mut hashes = self
-                    .block(BlockId::Forward(height))
-                    .next()
-                    .transpose()?
-                    .and_then(|(h, hashes)| if height == h { Some(hashes) } else { None })
-                    .unwrap_or_default();
-                hashes.push(hash.clone());
-                cache.hashes_at_height = Some((height, hashes.clone()));
-                hashes
-            }
-        };
-        drop(cache);
 
         let mut key = vec![];
-        hashes.binprot_write(&mut key).unwrap();
+        hash.binprot_write(&mut key).unwrap();
         let mut value = vec![];
         block.binprot_write(&mut value).unwrap();
+        let mut hashes = vec![];
+        vec![hash.clone()].binprot_write(&mut hashes).unwrap();
+        let mut parent = vec![];
+        block
+            .header
+            .protocol_state
+            .previous_state_hash
+            .binprot_write(&mut parent)
+            .unwrap();
+
+        let mut batch = WriteBatch::default();
+
+        let cf = self.inner.cf_handle("block").expect("must exist");
+        batch.put_cf(cf, key, value);
 
         let cf = self
             .inner
             .cf_handle("block_hash_by_height")
             .expect("must exist");
-        self.inner.put_cf(cf, height.to_be_bytes(), key.clone())?;
+        batch.merge_cf(cf, height.to_be_bytes(), &hashes);
 
-        let mut key = vec![];
-        hash.binprot_write(&mut key).unwrap();
+        let cf = self.inner.cf_handle("children").expect("must exist");
+        batch.merge_cf(cf, parent, &hashes);
 
-        let cf = self.inner.cf_handle("block").expect("must exist");
-        self.inner.put_cf(cf, key, value.clone())?;
+        self.inner.write(batch)?;
 
-        Ok(())
+        let _guard = self.canonical_lock.lock().expect("mutex");
+        storage::update_canonical(self, &hash, &block)
+    }
+
+    fn put_best_tip(&self, hash: v2::StateHash, removed: Range<u32>) -> Result<(), DbError> {
+        let mut batch = WriteBatch::default();
+        let cf = self.inner.cf_handle("canonical").expect("must exist");
+        for height in removed {
+            batch.delete_cf(cf, height.to_be_bytes());
+        }
+        let mut value = vec![];
+        hash.binprot_write(&mut value).unwrap();
+        let cf = self.inner.cf_handle("meta").expect("must exist");
+        batch.put_cf(cf, BEST_TIP_KEY, value);
+
+        self.inner.write(batch).map_err(Into::into)
+    }
+
+    fn put_canonical(&self, blocks: Vec<(u32, v2::StateHash)>) -> Result<(), DbError> {
+        let cf = self.inner.cf_handle("canonical").expect("must exist");
+        let mut batch = WriteBatch::default();
+        for (height, hash) in blocks {
+            let mut value = vec![];
+            hash.binprot_write(&mut value).unwrap();
+            batch.put_cf(cf, height.to_be_bytes(), value);
+        }
+
+        self.inner.write(batch).map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mina_p2p_messages::v2;
+
+    use super::{BlockId, Db, Storage, SCHEMA_VERSION};
+    use crate::{
+        proof::ProofStatus,
+        replay::ReplayResult,
+        testing::{empty, field, number, temp_dir},
+    };
+
+    /// The block at the height `1` on top of the unknown parent, the `fork` tells apart the blocks.
+    fn block(fork: u8) -> (v2::StateHash, v2::MinaBlockBlockStableV2) {
+        let mut block = empty::<v2::MinaBlockBlockStableV2>();
+        let protocol_state = &mut block.header.protocol_state;
+        protocol_state.body.consensus_state.blockchain_length = number(1);
+        protocol_state.body.blockchain_state.timestamp = number(fork);
+        protocol_state.previous_state_hash = field(0);
+
+        (block.hash(), block)
+    }
+
+    #[test]
+    fn merge_hashes_dedups() {
+        let path = temp_dir("merge");
+        let db = Db::open(&path).unwrap();
+
+        let (a, block_a) = block(0);
+        let (b, block_b) = block(1);
+        db.put_block(a.clone(), block_a.clone()).unwrap();
+        db.put_block(b.clone(), block_b).unwrap();
+        db.put_block(a.clone(), block_a).unwrap();
+
+        let heights = db
+            .block(BlockId::Forward(0))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(heights, vec![(1, vec![a.clone(), b.clone()])]);
+        assert_eq!(db.children(&field(0)).unwrap(), vec![a, b]);
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn verified_proofs_are_skipped() {
+        let path = temp_dir("proof");
+        let db = Db::open(&path).unwrap();
+
+        let (hash, block) = block(0);
+        db.put_block(hash.clone(), block).unwrap();
+        assert_eq!(db.proof_status(&hash).unwrap(), None);
+
+        db.put_proof_status(&hash, ProofStatus::Valid).unwrap();
+        assert_eq!(db.proof_status(&hash).unwrap(), Some(ProofStatus::Valid));
+        // the block has the status, so nothing is verified
+        assert_eq!(db.verify_proofs(false).unwrap(), (0, 0));
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn ledger_sync_is_keyed_by_ledger() {
+        let path = temp_dir("ledger-sync");
+        let db = Db::open(&path).unwrap();
+
+        let answer = v2::MinaLedgerSyncLedgerAnswerStableV2::ChildHashesAre(field(1), field(2));
+        db.put_ledger_sync_answer(&field(3), 2, 1, &answer).unwrap();
+        db.put_ledger_sync_answer(&field(4), 5, 7, &answer).unwrap();
+
+        let answers = db
+            .ledger_sync_answers(&field(4))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(answers.len(), 1);
+        assert_eq!((answers[0].0, answers[0].1), (5, 7));
+
+        db.clear_ledger_sync().unwrap();
+        assert!(db.ledger_sync_answers(&field(3)).next().is_none());
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn secondary_reads_through_ttl_suffix() {
+        let path = temp_dir("primary");
+        let secondary_path = temp_dir("secondary");
+
+        let db = Db::open(&path).unwrap();
+        db.put_root(1).unwrap();
+        let (hash, block) = block(0);
+        db.put_block(hash.clone(), block).unwrap();
+        let result = ReplayResult::Error {
+            message: "diverged".to_owned(),
+        };
+        db.put_replay_result(&hash, &result).unwrap();
+
+        let secondary = Db::open_secondary(&path, &secondary_path).unwrap();
+        assert_eq!(secondary.schema_version().unwrap(), Some(SCHEMA_VERSION));
+        assert_eq!(secondary.root().unwrap(), 1);
+        let heights = secondary
+            .block(BlockId::Forward(0))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(heights, vec![(1, vec![hash.clone()])]);
+        assert_eq!(secondary.block_full(&hash).unwrap().hash(), hash);
+        assert_eq!(secondary.best_tip().unwrap(), Some(hash.clone()));
+        assert_eq!(
+            secondary.replay_divergences().unwrap(),
+            vec![(hash, result)]
+        );
+
+        drop((db, secondary));
+        let _ = std::fs::remove_dir_all(path);
+        let _ = std::fs::remove_dir_all(secondary_path);
     }
 }
@@ -0,0 +1,293 @@
+use std::fmt;
+
+use mina_p2p_messages::binprot::{BinProtRead, BinProtWrite};
+use mina_p2p_messages::v2;
+use mina_tree::scan_state::scan_state::ConstraintConstants;
+
+use super::{Db, DbError, BlockId, BlockHeader, Storage};
+use crate::{replay, snarked_ledger::SnarkedLedger};
+
+/// Problems found by `Db::fsck`.
+#[derive(Default)]
+pub struct FsckReport {
+    /// Blocks stored under a key other than their hash, the key and the hash.
+    pub misplaced_blocks: Vec<(v2::StateHash, v2::StateHash)>,
+    /// Hashes in `block_hash_by_height` without a block of that height.
+    pub dangling_heights: Vec<(u32, v2::StateHash)>,
+    /// Hashes in `children` without a block, the parent and the child.
+    pub dangling_children: Vec<(v2::StateHash, v2::StateHash)>,
+    /// Unknown parents above the root, the height and the hash.
+    pub missing_parents: Vec<(u32, v2::StateHash)>,
+    /// The root ledger does not match the snarked ledger hash of the root block.
+    pub root_ledger: Option<String>,
+    /// The staged ledger built from the aux does not match the root block.
+    pub root_aux: Option<String>,
+    /// Number of fixed problems, only the indexes and the misplaced blocks can be fixed.
+    pub repaired: usize,
+}
+
+impl FsckReport {
+    pub fn is_ok(&self) -> bool {
+        self.misplaced_blocks.is_empty()
+            && self.dangling_heights.is_empty()
+            && self.dangling_children.is_empty()
+            && self.missing_parents.is_empty()
+            && self.root_ledger.is_none()
+            && self.root_aux.is_none()
+    }
+}
+
+impl fmt::Display for FsckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (key, hash) in &self.misplaced_blocks {
+            writeln!(f, "block {hash} is stored under {key}")?;
+        }
+        for (height, hash) in &self.dangling_heights {
+            writeln!(f, "no block {hash} at {height}")?;
+        }
+        for (parent, hash) in &self.dangling_children {
+            writeln!(f, "no block {hash}, a child of {parent}")?;
+        }
+        for (height, hash) in &self.missing_parents {
+            writeln!(f, "missing parent {hash} at {height}")?;
+        }
+        if let Some(problem) = &self.root_ledger {
+            writeln!(f, "root ledger: {problem}")?;
+        }
+        if let Some(problem) = &self.root_aux {
+            writeln!(f, "root aux: {problem}")?;
+        }
+        write!(f, "repaired {}", self.repaired)
+    }
+}
+
+impl Db {
+    /// Checks that the blocks are stored under their hashes, the indexes refer to stored blocks,
+    /// the parents are known, and the ledger and the aux of the root match the root block.
+    /// If `repair` is set, moves the misplaced blocks to their hashes, keeping a copy
+    /// in the quarantine, and removes dangling hashes from the indexes.
+    /// Missing parents are fetched by the archive, the root cannot be repaired.
+    pub fn fsck(
+        &self,
+        repair: bool,
+        ledger_depth: u8,
+        constants: &ConstraintConstants,
+    ) -> Result<FsckReport, DbError> {
+        let mut report = FsckReport::default();
+
+        self.check_blocks(repair, &mut report)?;
+        self.check_heights(repair, &mut report)?;
+        self.check_children(repair, &mut report)?;
+        report.missing_parents = self.missing_parents()?;
+        report.root_ledger = self.check_root_ledger(ledger_depth).err();
+        report.root_aux = replay::check_root(self, constants)
+            .err()
+            .map(|err| err.to_string());
+
+        Ok(report)
+    }
+
+    fn check_blocks(&self, repair: bool, report: &mut FsckReport) -> Result<(), DbError> {
+        let cf = self.inner.cf_handle("block").expect("must exist");
+        let mut misplaced = vec![];
+        for item in self.inner.iterator_cf(cf, rocksdb::IteratorMode::Start) {
+            let (key, value) = item?;
+            let key = v2::StateHash::binprot_read(&mut key.as_ref())?;
+            let block = v2::MinaBlockBlockStableV2::binprot_read(&mut value.as_ref())?;
+            let hash = block.hash();
+            if hash != key {
+                misplaced.push((key, hash, block));
+            }
+        }
+
+        for (key, hash, block) in misplaced {
+            report.misplaced_blocks.push((key.clone(), hash.clone()));
+            if repair {
+                let reason = format!("stored under a wrong hash, the hash is {hash}");
+                self.put_quarantined(key.clone(), reason, block.clone())?;
+                let mut key_bytes = vec![];
+                key.binprot_write(&mut key_bytes).unwrap();
+                self.inner.delete_cf(cf, key_bytes)?;
+                // the wrong key is removed from the indexes by the next checks
+                self.put_block(hash, block)?;
+                report.repaired += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_heights(&self, repair: bool, report: &mut FsckReport) -> Result<(), DbError> {
+        let heights = self
+            .block(BlockId::Forward(0))
+            .collect::<Result<Vec<_>, _>>()?;
+        let cf = self
+            .inner
+            .cf_handle("block_hash_by_height")
+            .expect("must exist");
+
+        for (height, hashes) in heights {
+            let mut valid = vec![];
+            let mut dangling = 0;
+            for hash in hashes {
+                match self.block_full(&hash) {
+                    Ok(block) if block.height() == height => valid.push(hash),
+                    Ok(_) | Err(DbError::BlockNotFound(_)) => {
+                        report.dangling_heights.push((height, hash));
+                        dangling += 1;
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            if repair && dangling != 0 {
+                self.put_hashes(cf, height.to_be_bytes(), valid)?;
+                report.repaired += dangling;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_children(&self, repair: bool, report: &mut FsckReport) -> Result<(), DbError> {
+        let cf = self.inner.cf_handle("children").expect("must exist");
+        let mut lists = vec![];
+        for item in self.inner.iterator_cf(cf, rocksdb::IteratorMode::Start) {
+            let (key, value) = item?;
+            let parent = v2::StateHash::binprot_read(&mut key.as_ref())?;
+            let children = Vec::<v2::StateHash>::binprot_read(&mut value.as_ref())?;
+            lists.push((key, parent, children));
+        }
+
+        for (key, parent, children) in lists {
+            let mut valid = vec![];
+            let mut dangling = 0;
+            for child in children {
+                if self.contains_block(&child)? {
+                    valid.push(child);
+                } else {
+                    report.dangling_children.push((parent.clone(), child));
+                    dangling += 1;
+                }
+            }
+            if repair && dangling != 0 {
+                self.put_hashes(cf, key, valid)?;
+                report.repaired += dangling;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the merged list of hashes, removes the key if the list is empty.
+    fn put_hashes<K>(
+        &self,
+        cf: &rocksdb::ColumnFamily,
+        key: K,
+        hashes: Vec<v2::StateHash>,
+    ) -> Result<(), DbError>
+    where
+        K: AsRef<[u8]>,
+    {
+        if hashes.is_empty() {
+            return self.inner.delete_cf(cf, key).map_err(Into::into);
+        }
+        let mut value = vec![];
+        hashes.binprot_write(&mut value).unwrap();
+        self.inner.put_cf(cf, key, value).map_err(Into::into)
+    }
+
+    fn check_root_ledger(&self, ledger_depth: u8) -> Result<(), String> {
+        let root = self.root().map_err(|err| err.to_string())?;
+        let hash = self
+            .canonical(root)
+            .map_err(|err| err.to_string())?
+            .ok_or_else(|| format!("no canonical block at the root {root}"))?;
+        let block = self.block_full(&hash).map_err(|err| err.to_string())?;
+        let expected = block.snarked_ledger_hash();
+        let accounts = self.ledger(&expected).map_err(|err| err.to_string())?;
+        let actual = SnarkedLedger::from_accounts(ledger_depth, &accounts).merkle_root();
+        if expected != actual {
+            return Err(format!("expected {expected}, actual {actual}"));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mina_p2p_messages::binprot::BinProtWrite;
+    use mina_p2p_messages::v2;
+
+    use super::{super::Db, FsckReport, Storage};
+    use crate::testing::{empty, field, number, temp_dir};
+
+    fn block(fork: u8) -> (v2::StateHash, v2::MinaBlockBlockStableV2) {
+        let mut block = empty::<v2::MinaBlockBlockStableV2>();
+        let protocol_state = &mut block.header.protocol_state;
+        protocol_state.body.consensus_state.blockchain_length = number(1);
+        protocol_state.body.blockchain_state.timestamp = number(fork);
+        protocol_state.previous_state_hash = field(0);
+
+        (block.hash(), block)
+    }
+
+    fn indexes(db: &Db, repair: bool) -> FsckReport {
+        let mut report = FsckReport::default();
+        db.check_blocks(repair, &mut report).unwrap();
+        db.check_heights(repair, &mut report).unwrap();
+        db.check_children(repair, &mut report).unwrap();
+        report
+    }
+
+    #[test]
+    fn dangling_hashes() {
+        let path = temp_dir("fsck-dangling");
+        let db = Db::open(&path).unwrap();
+
+        let (a, block_a) = block(0);
+        let (b, block_b) = block(1);
+        db.put_block(a.clone(), block_a).unwrap();
+        db.put_block(b.clone(), block_b).unwrap();
+        let mut key = vec![];
+        a.binprot_write(&mut key).unwrap();
+        let cf = db.inner.cf_handle("block").expect("must exist");
+        db.inner.delete_cf(cf, key).unwrap();
+
+        let report = indexes(&db, false);
+        assert_eq!(report.dangling_heights, vec![(1, a.clone())]);
+        assert_eq!(report.dangling_children, vec![(field(0), a.clone())]);
+        assert_eq!(report.repaired, 0);
+
+        assert_eq!(indexes(&db, true).repaired, 2);
+        assert!(indexes(&db, false).is_ok());
+        assert_eq!(db.children(&field(0)).unwrap(), vec![b]);
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn misplaced_block() {
+        let path = temp_dir("fsck-misplaced");
+        let db = Db::open(&path).unwrap();
+
+        let (hash, block) = block(0);
+        let wrong = field::<v2::StateHash>(5);
+        let (mut key, mut value) = (vec![], vec![]);
+        wrong.binprot_write(&mut key).unwrap();
+        block.binprot_write(&mut value).unwrap();
+        let cf = db.inner.cf_handle("block").expect("must exist");
+        db.inner.put_cf(cf, key, value).unwrap();
+
+        let report = indexes(&db, true);
+        assert_eq!(report.misplaced_blocks, vec![(wrong.clone(), hash.clone())]);
+        assert_eq!(report.repaired, 1);
+        assert!(!db.contains_block(&wrong).unwrap());
+        assert_eq!(db.block_full(&hash).unwrap().hash(), hash);
+        assert!(indexes(&db, false).is_ok());
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
@@ -0,0 +1,512 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Range,
+    sync::{Mutex, MutexGuard},
+};
+
+use mina_p2p_messages::v2;
+use mina_p2p_messages::rpc::GetStagedLedgerAuxAndPendingCoinbasesAtHashV2Response as Aux;
+
+use super::{storage, DbError, BlockId, BlockHeader, LedgerDiff, Storage};
+use crate::replay::ReplayResult;
+
+/// Keeps everything in memory and loses it on drop, for tests.
+#[derive(Default)]
+pub struct MemDb {
+    inner: Mutex<Inner>,
+    // serializes updates of the canonical chain
+    canonical_lock: Mutex<()>,
+}
+
+/// The same data as the column families of `Db`.
+#[derive(Default)]
+struct Inner {
+    roots: BTreeSet<u32>,
+    ledger: BTreeMap<v2::LedgerHash, Vec<v2::MinaBaseAccountBinableArgStableV2>>,
+    aux: BTreeMap<v2::StateHash, Aux>,
+    block: BTreeMap<v2::StateHash, v2::MinaBlockBlockStableV2>,
+    block_hash_by_height: BTreeMap<u32, Vec<v2::StateHash>>,
+    ledger_diff: BTreeMap<v2::StateHash, LedgerDiff>,
+    best_tip: Option<v2::StateHash>,
+    children: BTreeMap<v2::StateHash, Vec<v2::StateHash>>,
+    canonical: BTreeMap<u32, v2::StateHash>,
+    ledger_sync: BTreeMap<(v2::LedgerHash, i32, u32), v2::MinaLedgerSyncLedgerAnswerStableV2>,
+    // nothing reads the quarantine, it is kept for inspection
+    #[allow(dead_code)]
+    quarantine: BTreeMap<v2::StateHash, (String, v2::MinaBlockBlockStableV2)>,
+    replay: BTreeMap<v2::StateHash, ReplayResult>,
+}
+
+impl MemDb {
+    fn inner(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().expect("mutex")
+    }
+}
+
+/// Adds the hash to the list unless it is there, like the merge operator of `Db`.
+fn merge_hash(hashes: &mut Vec<v2::StateHash>, hash: &v2::StateHash) {
+    if !hashes.contains(hash) {
+        hashes.push(hash.clone());
+    }
+}
+
+impl Storage for MemDb {
+    fn root(&self) -> Result<u32, DbError> {
+        self.inner()
+            .roots
+            .first()
+            .copied()
+            .ok_or(DbError::RootNotFound)
+    }
+
+    fn block(
+        &self,
+        id: BlockId,
+    ) -> Box<dyn Iterator<Item = Result<(u32, Vec<v2::StateHash>), DbError>> + '_> {
+        let inner = self.inner();
+        let items = match id {
+            BlockId::Latest => inner
+                .block_hash_by_height
+                .iter()
+                .rev()
+                .map(|(height, hashes)| (*height, hashes.clone()))
+                .collect::<Vec<_>>(),
+            BlockId::Forward(pos) => inner
+                .block_hash_by_height
+                .range(pos..)
+                .map(|(height, hashes)| (*height, hashes.clone()))
+                .collect(),
+            BlockId::Canonical(pos) => inner
+                .canonical
+                .range(pos..)
+                .map(|(height, hash)| (*height, vec![hash.clone()]))
+                .collect(),
+        };
+
+        Box::new(items.into_iter().map(Ok))
+    }
+
+    fn contains_block(&self, hash: &v2::StateHash) -> Result<bool, DbError> {
+        Ok(self.inner().block.contains_key(hash))
+    }
+
+    fn block_full(&self, hash: &v2::StateHash) -> Result<v2::MinaBlockBlockStableV2, DbError> {
+        self.inner()
+            .block
+            .get(hash)
+            .cloned()
+            .ok_or_else(|| DbError::BlockNotFound(hash.clone()))
+    }
+
+    fn missing_parents(&self) -> Result<Vec<(u32, v2::StateHash)>, DbError> {
+        let root = self.root()?;
+        let inner = self.inner();
+        let mut missing = vec![];
+        for (parent, children) in &inner.children {
+            if inner.block.contains_key(parent) {
+                continue;
+            }
+            let Some(child) = children.first() else {
+                continue;
+            };
+            let child = inner
+                .block
+                .get(child)
+                .ok_or_else(|| DbError::BlockNotFound(child.clone()))?;
+            let height = child.height() - 1;
+            if height >= root {
+                missing.push((height, parent.clone()));
+            }
+        }
+
+        Ok(missing)
+    }
+
+    fn children(&self, hash: &v2::StateHash) -> Result<Vec<v2::StateHash>, DbError> {
+        Ok(self.inner().children.get(hash).cloned().unwrap_or_default())
+    }
+
+    fn canonical(&self, height: u32) -> Result<Option<v2::StateHash>, DbError> {
+        Ok(self.inner().canonical.get(&height).cloned())
+    }
+
+    fn best_tip(&self) -> Result<Option<v2::StateHash>, DbError> {
+        Ok(self.inner().best_tip.clone())
+    }
+
+    fn ledger(
+        &self,
+        hash: &v2::LedgerHash,
+    ) -> Result<Vec<v2::MinaBaseAccountBinableArgStableV2>, DbError> {
+        self.inner()
+            .ledger
+            .get(hash)
+            .cloned()
+            .ok_or_else(|| DbError::LedgerNotFound(hash.clone()))
+    }
+
+    fn ledger_diff(&self, hash: &v2::StateHash) -> Result<LedgerDiff, DbError> {
+        self.inner()
+            .ledger_diff
+            .get(hash)
+            .cloned()
+            .ok_or_else(|| DbError::LedgerDiffNotFound(hash.clone()))
+    }
+
+    fn aux(&self, hash: &v2::StateHash) -> Result<Aux, DbError> {
+        self.inner()
+            .aux
+            .get(hash)
+            .cloned()
+            .ok_or_else(|| DbError::AuxNotFound(hash.clone()))
+    }
+
+    fn ledger_sync_answers(
+        &self,
+        ledger: &v2::LedgerHash,
+    ) -> Box<
+        dyn Iterator<Item = Result<(i32, u32, v2::MinaLedgerSyncLedgerAnswerStableV2), DbError>>
+            + '_,
+    > {
+        let answers = self
+            .inner()
+            .ledger_sync
+            .iter()
+            .filter(|((hash, _, _), _)| hash == ledger)
+            .map(|((_, depth, pos), answer)| (*depth, *pos, answer.clone()))
+            .collect::<Vec<_>>();
+
+        Box::new(answers.into_iter().map(Ok))
+    }
+
+    fn replay_divergences(&self) -> Result<Vec<(v2::StateHash, ReplayResult)>, DbError> {
+        let divergences = self
+            .inner()
+            .replay
+            .iter()
+            .filter(|(_, result)| **result != ReplayResult::Ok)
+            .map(|(hash, result)| (hash.clone(), result.clone()))
+            .collect();
+
+        Ok(divergences)
+    }
+
+    fn put_root(&self, height: u32) -> Result<(), DbError> {
+        self.inner().roots.insert(height);
+
+        Ok(())
+    }
+
+    fn put_ledger(
+        &self,
+        hash: v2::LedgerHash,
+        ledger: Vec<v2::MinaBaseAccountBinableArgStableV2>,
+    ) -> Result<(), DbError> {
+        self.inner().ledger.insert(hash, ledger);
+
+        Ok(())
+    }
+
+    fn put_ledger_sync_answer(
+        &self,
+        ledger: &v2::LedgerHash,
+        depth: i32,
+        pos: u32,
+        answer: &v2::MinaLedgerSyncLedgerAnswerStableV2,
+    ) -> Result<(), DbError> {
+        self.inner()
+            .ledger_sync
+            .insert((ledger.clone(), depth, pos), answer.clone());
+
+        Ok(())
+    }
+
+    fn clear_ledger_sync(&self) -> Result<(), DbError> {
+        self.inner().ledger_sync.clear();
+
+        Ok(())
+    }
+
+    fn put_ledger_diff(&self, hash: v2::StateHash, diff: LedgerDiff) -> Result<(), DbError> {
+        self.inner().ledger_diff.insert(hash, diff);
+
+        Ok(())
+    }
+
+    fn put_aux(&self, hash: v2::StateHash, aux: Aux) -> Result<(), DbError> {
+        self.inner().aux.insert(hash, aux);
+
+        Ok(())
+    }
+
+    fn put_replay_result(
+        &self,
+        hash: &v2::StateHash,
+        result: &ReplayResult,
+    ) -> Result<(), DbError> {
+        self.inner().replay.insert(hash.clone(), result.clone());
+
+        Ok(())
+    }
+
+    fn put_quarantined(
+        &self,
+        hash: v2::StateHash,
+        reason: String,
+        block: v2::MinaBlockBlockStableV2,
+    ) -> Result<(), DbError> {
+        self.inner().quarantine.insert(hash, (reason, block));
+
+        Ok(())
+    }
+
+    fn put_block(
+        &self,
+        hash: v2::StateHash,
+        block: v2::MinaBlockBlockStableV2,
+    ) -> Result<(), DbError> {
+        {
+            let mut inner = self.inner();
+            let parent = block.header.protocol_state.previous_state_hash.clone();
+            merge_hash(
+                inner
+                    .block_hash_by_height
+                    .entry(block.height())
+                    .or_default(),
+                &hash,
+            );
+            merge_hash(inner.children.entry(parent).or_default(), &hash);
+            inner.block.insert(hash.clone(), block.clone());
+        }
+
+        let _guard = self.canonical_lock.lock().expect("mutex");
+        storage::update_canonical(self, &hash, &block)
+    }
+
+    fn put_best_tip(&self, hash: v2::StateHash, removed: Range<u32>) -> Result<(), DbError> {
+        let mut inner = self.inner();
+        for height in removed {
+            inner.canonical.remove(&height);
+        }
+        inner.best_tip = Some(hash);
+
+        Ok(())
+    }
+
+    fn put_canonical(&self, blocks: Vec<(u32, v2::StateHash)>) -> Result<(), DbError> {
+        self.inner().canonical.extend(blocks);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mina_p2p_messages::v2;
+
+    use super::{
+        super::{BlockHeader, BlockId, DbError, Storage},
+        MemDb,
+    };
+    use crate::testing::{empty, field, number};
+
+    /// The block on top of the parent. Every field is zero or empty except the parent
+    /// and the height, the `fork` tells apart the blocks of the same parent.
+    fn block(
+        parent: &v2::StateHash,
+        height: u8,
+        fork: u8,
+    ) -> (v2::StateHash, v2::MinaBlockBlockStableV2) {
+        let mut block = empty::<v2::MinaBlockBlockStableV2>();
+        let body = &mut block.header.protocol_state.body;
+        body.consensus_state.blockchain_length = number(height);
+        body.blockchain_state.timestamp = number(fork);
+        block.header.protocol_state.previous_state_hash = parent.clone();
+
+        (block.hash(), block)
+    }
+
+    fn with_ledger(
+        mut block: v2::MinaBlockBlockStableV2,
+        ledger: u8,
+    ) -> (v2::StateHash, v2::MinaBlockBlockStableV2) {
+        block
+            .header
+            .protocol_state
+            .body
+            .blockchain_state
+            .ledger_proof_statement
+            .target
+            .first_pass_ledger = field(ledger);
+
+        (block.hash(), block)
+    }
+
+    fn account(nonce: u8) -> v2::MinaBaseAccountBinableArgStableV2 {
+        let mut account = empty::<v2::MinaBaseAccountBinableArgStableV2>();
+        account.nonce = number(nonce);
+        account
+    }
+
+    /// The chain of `length` blocks from the height `1`, the root.
+    fn chain(db: &MemDb, length: u8) -> Vec<v2::StateHash> {
+        db.put_root(1).unwrap();
+        let mut hashes = vec![];
+        let mut parent = field(0);
+        for height in 1..=length {
+            let (hash, block) = block(&parent, height, 0);
+            db.put_block(hash.clone(), block).unwrap();
+            hashes.push(hash.clone());
+            parent = hash;
+        }
+
+        hashes
+    }
+
+    fn canonical(db: &MemDb) -> Vec<v2::StateHash> {
+        db.block(BlockId::Canonical(0))
+            .flat_map(|item| item.unwrap().1)
+            .collect()
+    }
+
+    #[test]
+    fn canonical_chain() {
+        let db = MemDb::default();
+        let hashes = chain(&db, 3);
+
+        assert_eq!(canonical(&db), hashes);
+        assert_eq!(db.best_tip().unwrap().as_ref(), hashes.last());
+    }
+
+    #[test]
+    fn reorg_to_longer_fork() {
+        let db = MemDb::default();
+        let hashes = chain(&db, 3);
+
+        let mut fork = vec![hashes[0].clone()];
+        for height in 2..=4 {
+            let (hash, block) = block(fork.last().unwrap(), height, 1);
+            db.put_block(hash.clone(), block).unwrap();
+            fork.push(hash);
+        }
+
+        assert_eq!(canonical(&db), fork);
+        assert_eq!(db.best_tip().unwrap().as_ref(), fork.last());
+        assert_eq!(db.block(BlockId::Forward(2)).count(), 3);
+    }
+
+    #[test]
+    fn insertion_order_does_not_matter() {
+        let (a, block_a) = block(&field(0), 1, 0);
+        let (b, block_b) = block(&a, 2, 0);
+        let (c, block_c) = block(&b, 3, 0);
+
+        let db = MemDb::default();
+        db.put_root(1).unwrap();
+        db.put_block(a.clone(), block_a.clone()).unwrap();
+        db.put_block(c.clone(), block_c.clone()).unwrap();
+        // `b` is missing, `c` alone is the best tip
+        assert_eq!(db.missing_parents().unwrap(), [(2, b.clone())]);
+        assert_eq!(canonical(&db), [a.clone(), c.clone()]);
+
+        db.put_block(b.clone(), block_b.clone()).unwrap();
+        assert!(db.missing_parents().unwrap().is_empty());
+        assert_eq!(canonical(&db), [a.clone(), b.clone(), c.clone()]);
+
+        let in_order = MemDb::default();
+        in_order.put_root(1).unwrap();
+        for (hash, block) in [(a, block_a), (b, block_b), (c.clone(), block_c)] {
+            in_order.put_block(hash, block).unwrap();
+        }
+        assert_eq!(canonical(&in_order), canonical(&db));
+        assert_eq!(in_order.best_tip().unwrap(), Some(c));
+    }
+
+    #[test]
+    fn missing_parents_above_root() {
+        let db = MemDb::default();
+        let hashes = chain(&db, 2);
+        // the parent of the root is below the root, it is not missing
+        assert!(db.missing_parents().unwrap().is_empty());
+
+        let (hash, orphan) = block(&field(1), 5, 0);
+        db.put_block(hash, orphan).unwrap();
+        assert_eq!(db.missing_parents().unwrap(), [(4, field(1))]);
+        assert_eq!(db.missing_heights().unwrap(), [3, 4]);
+
+        let (hash, below) = block(&field(2), 1, 1);
+        db.put_block(hash, below).unwrap();
+        assert_eq!(db.missing_parents().unwrap(), [(4, field(1))]);
+        assert_eq!(db.children(&hashes[0]).unwrap(), [hashes[1].clone()]);
+    }
+
+    #[test]
+    fn chain_to() {
+        let db = MemDb::default();
+        let hashes = chain(&db, 4);
+
+        let chain = db.chain_to(&hashes[3], 2).unwrap();
+        let heights = chain.iter().map(BlockHeader::height).collect::<Vec<_>>();
+        assert_eq!(heights, [2, 3, 4]);
+        // stops at the unknown parent of the root
+        assert_eq!(db.chain_to(&hashes[3], 10).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn ledger_at() {
+        let db = MemDb::default();
+        db.put_root(1).unwrap();
+        db.put_ledger(field(10), vec![account(0), account(1)])
+            .unwrap();
+
+        let (_, root) = block(&field(0), 1, 0);
+        let (a, root) = with_ledger(root, 10);
+        let (b, block_b) = block(&a, 2, 0);
+        let (b, block_b) = with_ledger(block_b, 10);
+        let (c, block_c) = block(&b, 3, 0);
+        let (c, block_c) = with_ledger(block_c, 11);
+        for (hash, block) in [(a, root), (b.clone(), block_b), (c.clone(), block_c)] {
+            db.put_block(hash, block).unwrap();
+        }
+        // the diff of `c` updates an account and appends one
+        db.put_ledger_diff(c.clone(), vec![(1, account(2)), (2, account(3))])
+            .unwrap();
+
+        assert_eq!(db.ledger_at(&b).unwrap(), [account(0), account(1)]);
+        assert_eq!(
+            db.ledger_at(&c).unwrap(),
+            [account(0), account(2), account(3)]
+        );
+
+        db.put_ledger_diff(c.clone(), vec![(5, account(4))])
+            .unwrap();
+        assert!(matches!(db.ledger_at(&c), Err(DbError::BadIndex)));
+    }
+
+    #[test]
+    fn ledger_at_caches() {
+        let db = MemDb::default();
+        db.put_root(1).unwrap();
+        db.put_ledger(field(10), vec![account(0)]).unwrap();
+
+        let (mut parent, root) = with_ledger(block(&field(0), 1, 0).1, 10);
+        db.put_block(parent.clone(), root).unwrap();
+        let mut hashes = vec![];
+        for n in 1..=20 {
+            let (hash, block) = with_ledger(block(&parent, n + 1, 0).1, 10 + n);
+            db.put_block(hash.clone(), block).unwrap();
+            db.put_ledger_diff(hash.clone(), vec![(0, account(n))])
+                .unwrap();
+            hashes.push(hash.clone());
+            parent = hash;
+        }
+
+        assert_eq!(db.ledger_at(&hashes[15]).unwrap(), [account(16)]);
+        assert_eq!(db.ledger(&field(26)).unwrap(), [account(16)]);
+
+        // the later call starts from the stored ledger, the broken diff below is not folded
+        db.put_ledger_diff(hashes[0].clone(), vec![(5, account(0))])
+            .unwrap();
+        assert_eq!(db.ledger_at(&hashes[19]).unwrap(), [account(20)]);
+    }
+}
@@ -0,0 +1,165 @@
+use std::fmt;
+
+use mina_p2p_messages::binprot::{BinProtRead, BinProtWrite};
+use mina_p2p_messages::v2;
+
+use super::{Db, DbError, BlockId, Storage};
+
+/// A step that brings the database from `version - 1` to `version`.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    /// Performs the step, or only counts affected records if `dry_run` is set.
+    /// Returns the number of affected records.
+    pub run: fn(db: &Db, dry_run: bool) -> Result<usize, DbError>,
+}
+
+/// All migration steps, in order. The step at index `i` has version `i + 1`.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "remove duplicated hashes from `block_hash_by_height`",
+        run: dedup_block_hash_by_height,
+    },
+    Migration {
+        version: 2,
+        description: "build `children` index and mark the canonical chain",
+        run: index_children_and_canonical,
+    },
+    Migration {
+        version: 3,
+        description: "drop the ledger sync progress that is not keyed by the ledger",
+        run: drop_unkeyed_ledger_sync,
+    },
+];
+
+/// The version of the layout this build works with.
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+pub struct MigrationReport {
+    pub version: u32,
+    pub description: &'static str,
+    pub affected: usize,
+    pub dry_run: bool,
+}
+
+impl fmt::Display for MigrationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verb = if self.dry_run {
+            "would affect"
+        } else {
+            "affected"
+        };
+        write!(
+            f,
+            "migration to version {}: {}, {verb} {} records",
+            self.version, self.description, self.affected
+        )
+    }
+}
+
+fn dedup_block_hash_by_height(db: &Db, dry_run: bool) -> Result<usize, DbError> {
+    let cf = db
+        .inner
+        .cf_handle("block_hash_by_height")
+        .expect("must exist");
+
+    let mut affected = 0;
+    for item in db.inner.iterator_cf(cf, rocksdb::IteratorMode::Start) {
+        let (key, value) = item?;
+        let mut slice = value.as_ref();
+        let hashes = Vec::<v2::StateHash>::binprot_read(&mut slice)?;
+        let mut unique = Vec::with_capacity(hashes.len());
+        for hash in &hashes {
+            if !unique.contains(hash) {
+                unique.push(hash.clone());
+            }
+        }
+        if unique.len() == hashes.len() {
+            continue;
+        }
+
+        affected += 1;
+        if !dry_run {
+            let mut value = vec![];
+            unique.binprot_write(&mut value).unwrap();
+            db.inner.put_cf(cf, key, value)?;
+        }
+    }
+
+    Ok(affected)
+}
+
+fn index_children_and_canonical(db: &Db, dry_run: bool) -> Result<usize, DbError> {
+    let heights = db
+        .block(BlockId::Forward(0))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut affected = 0;
+    for (height, hashes) in heights {
+        for hash in hashes {
+            let block = match db.block_full(&hash) {
+                Ok(v) => v,
+                Err(DbError::BlockNotFound(_)) => {
+                    log::warn!("block {hash} at {height} is indexed, but not stored");
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            affected += 1;
+            if !dry_run {
+                // storing the block again updates the indexes
+                db.put_block(hash, block)?;
+            }
+        }
+    }
+
+    Ok(affected)
+}
+
+fn drop_unkeyed_ledger_sync(db: &Db, dry_run: bool) -> Result<usize, DbError> {
+    // the dry run opens the database as it is, it may predate the column family
+    let Some(cf) = db.inner.cf_handle("ledger_sync") else {
+        return Ok(0);
+    };
+
+    let mut affected = 0;
+    for item in db.inner.iterator_cf(cf, rocksdb::IteratorMode::Start) {
+        let (key, _) = item?;
+        affected += 1;
+        if !dry_run {
+            db.inner.delete_cf(cf, key)?;
+        }
+    }
+
+    Ok(affected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::Db, MIGRATIONS, SCHEMA_VERSION};
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn versions_are_sequential() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version as usize, i + 1);
+        }
+        assert_eq!(SCHEMA_VERSION, MIGRATIONS.last().unwrap().version);
+    }
+
+    #[test]
+    fn fresh_database_is_current() {
+        let path = temp_dir("migration");
+        let db = Db::open(&path).unwrap();
+        assert_eq!(db.schema_version().unwrap(), Some(SCHEMA_VERSION));
+        assert!(db.migrate(false).unwrap().is_empty());
+
+        drop(db);
+        let dry_run = Db::open_read_only(&path).unwrap();
+        assert!(dry_run.migrate(true).unwrap().is_empty());
+
+        drop(dry_run);
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
@@ -0,0 +1,313 @@
+use std::ops::Range;
+
+use mina_p2p_messages::v2;
+use mina_p2p_messages::rpc::GetStagedLedgerAuxAndPendingCoinbasesAtHashV2Response as Aux;
+
+use super::{DbError, BlockId, BlockHeader, LedgerDiff, FRONTIER_LENGTH};
+use crate::{consensus, replay::ReplayResult};
+
+/// How many diffs `Storage::ledger_at` folds before it stores the rebuilt ledger.
+const LEDGER_CACHE_DIFFS: usize = 16;
+
+/// The storage of the archive, implemented by the RocksDB `Db` and the in-memory `MemDb`.
+pub trait Storage: Send + Sync + 'static {
+    /// The height of the root, the lowest block that has the ledger and the aux.
+    fn root(&self) -> Result<u32, DbError>;
+
+    /// Hashes of the blocks by height, see `BlockId`.
+    fn block(
+        &self,
+        id: BlockId,
+    ) -> Box<dyn Iterator<Item = Result<(u32, Vec<v2::StateHash>), DbError>> + '_>;
+
+    fn contains_block(&self, hash: &v2::StateHash) -> Result<bool, DbError>;
+
+    fn block_full(&self, hash: &v2::StateHash) -> Result<v2::MinaBlockBlockStableV2, DbError>;
+
+    /// Parents that are referenced by stored blocks above the root, but not stored.
+    /// Returns the height and the hash of each missing parent.
+    /// Scans the whole children index, so the gaps are found once and then walked back.
+    fn missing_parents(&self) -> Result<Vec<(u32, v2::StateHash)>, DbError>;
+
+    fn children(&self, hash: &v2::StateHash) -> Result<Vec<v2::StateHash>, DbError>;
+
+    fn canonical(&self, height: u32) -> Result<Option<v2::StateHash>, DbError>;
+
+    fn best_tip(&self) -> Result<Option<v2::StateHash>, DbError>;
+
+    fn ledger(
+        &self,
+        hash: &v2::LedgerHash,
+    ) -> Result<Vec<v2::MinaBaseAccountBinableArgStableV2>, DbError>;
+
+    fn ledger_diff(&self, hash: &v2::StateHash) -> Result<LedgerDiff, DbError>;
+
+    fn aux(&self, hash: &v2::StateHash) -> Result<Aux, DbError>;
+
+    /// Answers received by the unfinished sync of the ledger,
+    /// with the depth and the position of the subtree.
+    fn ledger_sync_answers(
+        &self,
+        ledger: &v2::LedgerHash,
+    ) -> Box<
+        dyn Iterator<Item = Result<(i32, u32, v2::MinaLedgerSyncLedgerAnswerStableV2), DbError>>
+            + '_,
+    >;
+
+    /// The replay results of the blocks that diverged from the staged ledger hash in the header.
+    fn replay_divergences(&self) -> Result<Vec<(v2::StateHash, ReplayResult)>, DbError>;
+
+    fn put_root(&self, height: u32) -> Result<(), DbError>;
+
+    fn put_ledger(
+        &self,
+        hash: v2::LedgerHash,
+        ledger: Vec<v2::MinaBaseAccountBinableArgStableV2>,
+    ) -> Result<(), DbError>;
+
+    fn put_ledger_sync_answer(
+        &self,
+        ledger: &v2::LedgerHash,
+        depth: i32,
+        pos: u32,
+        answer: &v2::MinaLedgerSyncLedgerAnswerStableV2,
+    ) -> Result<(), DbError>;
+
+    /// Removes the progress of the sync of every ledger, when the ledger is stored.
+    fn clear_ledger_sync(&self) -> Result<(), DbError>;
+
+    fn put_ledger_diff(&self, hash: v2::StateHash, diff: LedgerDiff) -> Result<(), DbError>;
+
+    fn put_aux(&self, hash: v2::StateHash, aux: Aux) -> Result<(), DbError>;
+
+    fn put_replay_result(&self, hash: &v2::StateHash, result: &ReplayResult)
+        -> Result<(), DbError>;
+
+    /// Stores the block that failed validation aside from the main block store.
+    fn put_quarantined(
+        &self,
+        hash: v2::StateHash,
+        reason: String,
+        block: v2::MinaBlockBlockStableV2,
+    ) -> Result<(), DbError>;
+
+    /// Stores the block and adds its hash to the height and children indexes,
+    /// then updates the canonical chain by `update_canonical`.
+    /// Storing the same block again is a no-op, the order of insertion does not matter.
+    fn put_block(
+        &self,
+        hash: v2::StateHash,
+        block: v2::MinaBlockBlockStableV2,
+    ) -> Result<(), DbError>;
+
+    /// Sets the best tip and removes the canonical blocks at the given heights at once.
+    fn put_best_tip(&self, hash: v2::StateHash, removed: Range<u32>) -> Result<(), DbError>;
+
+    /// Marks the blocks as canonical at their heights at once.
+    fn put_canonical(&self, blocks: Vec<(u32, v2::StateHash)>) -> Result<(), DbError>;
+
+    /// Heights between the root and the head that have no block.
+    fn missing_heights(&self) -> Result<Vec<u32>, DbError> {
+        let root = self.root()?;
+        let mut missing = vec![];
+        let mut expected = root;
+        for item in self.block(BlockId::Forward(root)) {
+            let (height, _) = item?;
+            missing.extend(expected..height);
+            expected = height + 1;
+        }
+
+        Ok(missing)
+    }
+
+    /// Rebuild the snarked ledger at the given block by folding the diffs of the blocks
+    /// onto the nearest stored ledger, the root ledger if there is no closer one.
+    /// The rebuilt ledger is stored if it took `LEDGER_CACHE_DIFFS` diffs or more,
+    /// so the later calls start from it.
+    fn ledger_at(
+        &self,
+        hash: &v2::StateHash,
+    ) -> Result<Vec<v2::MinaBaseAccountBinableArgStableV2>, DbError> {
+        let root = self.root()?;
+
+        let mut diffs = vec![];
+        let mut hash = hash.clone();
+        let mut block = self.block_full(&hash)?;
+        let target = block.snarked_ledger_hash();
+        let mut ledger = loop {
+            match self.ledger(&block.snarked_ledger_hash()) {
+                Ok(ledger) => break ledger,
+                Err(DbError::LedgerNotFound(_)) if block.height() > root => {}
+                Err(err) => return Err(err),
+            }
+            // walk back to the previous snarked ledger
+            loop {
+                let prev_hash = block.header.protocol_state.previous_state_hash.clone();
+                let prev = self.block_full(&prev_hash)?;
+                let changed = block.snarked_ledger_hash() != prev.snarked_ledger_hash();
+                if changed {
+                    diffs.push(self.ledger_diff(&hash)?);
+                }
+                hash = prev_hash;
+                block = prev;
+                if changed || block.height() <= root {
+                    break;
+                }
+            }
+        };
+
+        let folded = diffs.len();
+        for diff in diffs.into_iter().rev() {
+            for (pos, account) in diff {
+                let pos = pos as usize;
+                if pos < ledger.len() {
+                    ledger[pos] = account;
+                } else if pos == ledger.len() {
+                    ledger.push(account);
+                } else {
+                    return Err(DbError::BadIndex);
+                }
+            }
+        }
+
+        if folded >= LEDGER_CACHE_DIFFS {
+            // a read-only storage still answers, it just does not cache
+            if let Err(err) = self.put_ledger(target.clone(), ledger.clone()) {
+                log::warn!("cannot store the ledger {target}: {err}");
+            }
+        }
+
+        Ok(ledger)
+    }
+
+    /// Finds the snarked ledger among the stored root ledger
+    /// and the ledgers of the recent blocks of the canonical chain.
+    fn snarked_ledger(
+        &self,
+        hash: &v2::LedgerHash,
+    ) -> Result<Vec<v2::MinaBaseAccountBinableArgStableV2>, DbError> {
+        match self.ledger(hash) {
+            Err(DbError::LedgerNotFound(_)) => {}
+            result => return result,
+        }
+
+        let not_found = || DbError::LedgerNotFound(hash.clone());
+        let mut block_hash = self.best_tip()?.ok_or_else(not_found)?;
+        for _ in 0..=FRONTIER_LENGTH {
+            let block = match self.block_full(&block_hash) {
+                Ok(v) => v,
+                Err(DbError::BlockNotFound(_)) => break,
+                Err(err) => return Err(err),
+            };
+            if block.snarked_ledger_hash() == *hash {
+                return self.ledger_at(&block_hash);
+            }
+            block_hash = block.header.protocol_state.previous_state_hash;
+        }
+
+        Err(not_found())
+    }
+
+    /// The chain of blocks ending at the given one, oldest first. Contains at most
+    /// `max_length` ancestors, less if some ancestor is unknown.
+    fn chain_to(
+        &self,
+        hash: &v2::StateHash,
+        max_length: u32,
+    ) -> Result<Vec<v2::MinaBlockBlockStableV2>, DbError> {
+        let mut chain = vec![self.block_full(hash)?];
+        for _ in 0..max_length {
+            let prev_hash = &chain[chain.len() - 1]
+                .header
+                .protocol_state
+                .previous_state_hash;
+            match self.block_full(prev_hash) {
+                Ok(block) => chain.push(block),
+                Err(DbError::BlockNotFound(_)) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        chain.reverse();
+
+        Ok(chain)
+    }
+}
+
+/// Marks the chain ending at the given block as canonical,
+/// walks back until it meets the already canonical block or an unknown parent.
+fn mark_canonical<D>(
+    db: &D,
+    hash: v2::StateHash,
+    block: v2::MinaBlockBlockStableV2,
+) -> Result<(), DbError>
+where
+    D: Storage + ?Sized,
+{
+    let mut blocks = vec![];
+
+    let (mut hash, mut block) = (hash, block);
+    loop {
+        let height = block.height();
+        if db.canonical(height)?.as_ref() == Some(&hash) {
+            break;
+        }
+        blocks.push((height, hash));
+
+        hash = block.header.protocol_state.previous_state_hash.clone();
+        block = match db.block_full(&hash) {
+            Ok(v) => v,
+            Err(DbError::BlockNotFound(_)) => break,
+            Err(err) => return Err(err),
+        };
+    }
+
+    db.put_canonical(blocks)
+}
+
+/// Applies the chain selection rules to the new block, moves the best tip on reorg.
+/// Called by `Storage::put_block` after the block is stored, the caller serializes the calls.
+pub(super) fn update_canonical<D>(
+    db: &D,
+    hash: &v2::StateHash,
+    block: &v2::MinaBlockBlockStableV2,
+) -> Result<(), DbError>
+where
+    D: Storage + ?Sized,
+{
+    // only the new block is compared with the best tip,
+    // the descendants stored before it were compared when they came
+    let current = match db.best_tip()? {
+        Some(hash) => Some((db.block_full(&hash)?, hash)),
+        None => None,
+    };
+    let take = match &current {
+        None => true,
+        Some((current, current_hash)) => {
+            current_hash != hash && consensus::select(current, current_hash, block, hash)
+        }
+    };
+
+    if take {
+        let removed = match &current {
+            Some((current, _)) => (block.height() + 1)..(current.height() + 1),
+            None => 0..0,
+        };
+        db.put_best_tip(hash.clone(), removed)?;
+
+        if let Some((_, current_hash)) = &current {
+            log::info!("best tip {current_hash} -> {hash}");
+        }
+        mark_canonical(db, hash.clone(), block.clone())
+    } else {
+        // the block may fill a gap in the canonical chain
+        let Some(next_hash) = db.canonical(block.height() + 1)? else {
+            return Ok(());
+        };
+        let next = db.block_full(&next_hash)?;
+        if next.header.protocol_state.previous_state_hash == *hash {
+            mark_canonical(db, hash.clone(), block.clone())
+        } else {
+            Ok(())
+        }
+    }
+}
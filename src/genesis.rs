@@ -0,0 +1,134 @@
+use std::{fs::File, io, path::Path};
+
+use mina_p2p_messages::v2;
+use mina_signer::CompressedPubKey;
+use mina_tree::{
+    scan_state::currency::{Amount, Balance, Nonce, Slot, SlotSpan},
+    Account, Timing,
+};
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+    #[error("bad public key {0}")]
+    BadPublicKey(String),
+    #[error("bad number {0}")]
+    BadNumber(serde_json::Value),
+}
+
+/// The part of the Mina runtime config describing the genesis ledger.
+#[derive(Deserialize)]
+struct RuntimeConfig {
+    ledger: LedgerConfig,
+}
+
+#[derive(Deserialize)]
+struct LedgerConfig {
+    accounts: Vec<AccountConfig>,
+}
+
+#[derive(Deserialize)]
+struct AccountConfig {
+    pk: String,
+    balance: String,
+    #[serde(default)]
+    delegate: Option<String>,
+    #[serde(default)]
+    nonce: Option<serde_json::Value>,
+    #[serde(default)]
+    timing: Option<TimingConfig>,
+}
+
+#[derive(Deserialize)]
+struct TimingConfig {
+    initial_minimum_balance: String,
+    cliff_time: serde_json::Value,
+    cliff_amount: String,
+    vesting_period: serde_json::Value,
+    vesting_increment: String,
+}
+
+// numbers are usually strings in the runtime config
+fn number(value: &serde_json::Value) -> Result<u32, Error> {
+    match value {
+        serde_json::Value::String(s) => s.parse().ok(),
+        serde_json::Value::Number(n) => n.as_u64().and_then(|n| n.try_into().ok()),
+        _ => None,
+    }
+    .ok_or_else(|| Error::BadNumber(value.clone()))
+}
+
+// amounts are decimal mina strings with at most 9 digits after the point
+fn nanomina(value: &str) -> Result<u64, Error> {
+    let bad = || Error::BadNumber(serde_json::Value::String(value.to_owned()));
+    let (whole, fraction) = value.split_once('.').unwrap_or((value, ""));
+    if fraction.len() > 9 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(bad());
+    }
+    let whole = whole.parse::<u64>().map_err(|_| bad())?;
+    let fraction = format!("{fraction:0<9}")
+        .parse::<u64>()
+        .map_err(|_| bad())?;
+    whole
+        .checked_mul(1_000_000_000)
+        .and_then(|whole| whole.checked_add(fraction))
+        .ok_or_else(bad)
+}
+
+fn public_key(address: &str) -> Result<CompressedPubKey, Error> {
+    CompressedPubKey::from_address(address).map_err(|_| Error::BadPublicKey(address.to_owned()))
+}
+
+impl AccountConfig {
+    fn to_account(&self) -> Result<Account, Error> {
+        let mut account = Account::empty();
+        account.public_key = public_key(&self.pk)?;
+        account.balance = Balance::from_u64(nanomina(&self.balance)?);
+        account.delegate = match &self.delegate {
+            Some(delegate) => Some(public_key(delegate)?),
+            None => Some(account.public_key.clone()),
+        };
+        if let Some(nonce) = &self.nonce {
+            account.nonce = Nonce::from_u32(number(nonce)?);
+        }
+        if let Some(timing) = &self.timing {
+            account.timing = Timing::Timed {
+                initial_minimum_balance: Balance::from_u64(nanomina(
+                    &timing.initial_minimum_balance,
+                )?),
+                cliff_time: Slot::from_u32(number(&timing.cliff_time)?),
+                cliff_amount: Amount::from_u64(nanomina(&timing.cliff_amount)?),
+                vesting_period: SlotSpan::from_u32(number(&timing.vesting_period)?),
+                vesting_increment: Amount::from_u64(nanomina(&timing.vesting_increment)?),
+            };
+        }
+
+        Ok(account)
+    }
+}
+
+/// Loads the genesis ledger from the runtime config, the account of the genesis block winner
+/// is not in the config and goes at the position `0`.
+pub fn load<P>(path: P, winner: &str) -> Result<Vec<v2::MinaBaseAccountBinableArgStableV2>, Error>
+where
+    P: AsRef<Path>,
+{
+    let config = serde_json::from_reader::<_, RuntimeConfig>(File::open(path)?)?;
+
+    let mut winner_account = Account::empty();
+    winner_account.public_key = public_key(winner)?;
+    winner_account.balance = Balance::from_u64(1000);
+    winner_account.delegate = Some(winner_account.public_key.clone());
+
+    let mut accounts = vec![(&winner_account).into()];
+    for account in &config.ledger.accounts {
+        accounts.push((&account.to_account()?).into());
+    }
+
+    Ok(accounts)
+}
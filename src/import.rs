@@ -0,0 +1,108 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use mina_p2p_messages::{
+    binprot::{self, BinProtRead},
+    rpc::GetStagedLedgerAuxAndPendingCoinbasesAtHashV2Response as Aux,
+    v2,
+};
+use openmina_archive_profile::IndexEntry;
+use thiserror::Error;
+
+use super::{
+    db::{DbError, BlockHeader, Storage},
+    snarked_ledger::SnarkedLedger,
+    validation::{self, ValidationError},
+};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+    #[error("{0}")]
+    Binprot(#[from] binprot::Error),
+    #[error("{0}")]
+    Db(#[from] DbError),
+    #[error("invalid block {0}")]
+    Invalid(#[from] ValidationError),
+    #[error("the database already has the root {0}")]
+    NotEmpty(u32),
+    #[error("no block of the first level has the snarked ledger {0}")]
+    NoRootBlock(v2::LedgerHash),
+    #[error("several blocks of the first level have the snarked ledger {0}, choose the root")]
+    AmbiguousRoot(v2::LedgerHash),
+    #[error("the root {0} is not a block of the first level with the ledger of the backup")]
+    BadRoot(v2::StateHash),
+}
+
+/// Restores the backup written by `tester backup` into a database without a root.
+/// The root is the block of the first level whose snarked ledger is the ledger of the backup.
+/// The forks of the first level may share the ledger, then the root must be given by its hash.
+/// The root is stored last, so an interrupted import can be run again.
+/// The backup has no ledger diffs, the daemon syncs them on start.
+pub fn run<D>(
+    db: &D,
+    path: &Path,
+    ledger_depth: u8,
+    root: Option<v2::StateHash>,
+) -> Result<(), Error>
+where
+    D: Storage,
+{
+    if let Ok(root) = db.root() {
+        return Err(Error::NotEmpty(root));
+    }
+
+    let mut ledger_file = BufReader::new(File::open(path.join("ledger"))?);
+    let (accounts, aux) =
+        <(Vec<v2::MinaBaseAccountBinableArgStableV2>, Aux)>::binprot_read(&mut ledger_file)?;
+    let ledger_hash = SnarkedLedger::from_accounts(ledger_depth, &accounts).merkle_root();
+    log::info!("ledger {ledger_hash}, {} accounts", accounts.len());
+
+    let index = serde_json::from_reader::<_, Vec<IndexEntry>>(File::open(path.join("index"))?)?;
+    let mut blocks_file = File::open(path.join("blocks"))?;
+    let mut chosen = None;
+    for entry in index {
+        blocks_file.seek(SeekFrom::Start(entry.offset))?;
+        let mut reader = BufReader::new((&mut blocks_file).take(entry.length));
+        let blocks = Vec::<v2::MinaBlockBlockStableV2>::binprot_read(&mut reader)?;
+        let mut candidates = vec![];
+        for block in blocks {
+            let hash = validation::check(&block, None)?;
+            if chosen.is_none() && block.snarked_ledger_hash() == ledger_hash {
+                candidates.push((block.height(), hash.clone()));
+            }
+            db.put_block(hash, block)?;
+        }
+        if chosen.is_none() {
+            chosen = Some(match &root {
+                Some(root) => candidates
+                    .into_iter()
+                    .find(|(_, hash)| hash == root)
+                    .ok_or_else(|| Error::BadRoot(root.clone()))?,
+                None if candidates.len() > 1 => {
+                    return Err(Error::AmbiguousRoot(ledger_hash));
+                }
+                None => candidates
+                    .pop()
+                    .ok_or_else(|| Error::NoRootBlock(ledger_hash.clone()))?,
+            });
+        }
+        log::info!("imported {}", entry.height);
+    }
+    let Some((height, hash)) = chosen else {
+        return Err(Error::NoRootBlock(ledger_hash));
+    };
+
+    db.put_ledger(ledger_hash, accounts)?;
+    db.put_aux(hash.clone(), aux)?;
+    db.put_root(height)?;
+    log::info!("root {height} {hash}");
+
+    Ok(())
+}
@@ -3,12 +3,21 @@
 // * add http for test launch/stop/status
 
 mod db;
+mod consensus;
 mod main_loop;
 mod client;
 mod snarked_ledger;
 mod server;
+mod proof;
+mod replay;
+mod validation;
+mod genesis;
+mod import;
+mod snapshot;
+#[cfg(test)]
+mod testing;
 
-use std::{path::PathBuf, env, sync::Arc};
+use std::{path::PathBuf, env, sync::Arc, time::Duration};
 
 use libp2p::{
     Multiaddr,
@@ -18,22 +27,80 @@ use libp2p::{
     },
 };
 
-use tokio::sync::mpsc;
+use tokio::{sync::mpsc, signal, time};
 
 use structopt::StructOpt;
 
+use openmina_archive_profile::NetworkProfile;
+
 #[derive(StructOpt)]
 struct Args {
     #[structopt(long)]
     path: PathBuf,
+    /// The name of a built-in network profile, or the path to the profile file
+    #[structopt(long, default_value = "berkeley")]
+    network: NetworkProfile,
+    /// Overrides the chain id of the network profile, required if the profile does not have it
     #[structopt(long)]
-    chain_id: String,
+    chain_id: Option<String>,
     #[structopt(long)]
     listen: Vec<Multiaddr>,
     #[structopt(long)]
     peer: Vec<Multiaddr>,
     #[structopt(long)]
     http: Option<u16>,
+    /// Report pending database migrations and exit
+    #[structopt(long)]
+    migrate_dry_run: bool,
+    /// Archive from the genesis block, the genesis ledger is loaded from the Mina runtime config
+    #[structopt(long)]
+    genesis: Option<PathBuf>,
+    /// Overrides the account that won the genesis block of the network profile
+    #[structopt(long)]
+    genesis_winner: Option<String>,
+    /// Where to create snapshots of the database, enables the `/snapshot` endpoint
+    #[structopt(long)]
+    snapshot_dir: Option<PathBuf>,
+    /// How many latest snapshots to keep, all if `0`
+    #[structopt(long, default_value = "7")]
+    snapshot_keep: usize,
+    /// Serve the database of a running archive read-only, without the p2p part,
+    /// the RocksDB secondary instance keeps its own files in this directory
+    #[structopt(long, requires = "http")]
+    secondary: Option<PathBuf>,
+    /// How often the read-only instance catches up with the archive, in seconds
+    #[structopt(long, default_value = "5")]
+    catch_up_interval: u64,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Verify the protocol state proofs of the stored blocks and exit
+    VerifyProofs {
+        /// Verify again the blocks that already have the status
+        #[structopt(long)]
+        redo: bool,
+    },
+    /// Restore a backup written by `tester backup` into a fresh database and exit
+    Import {
+        /// The directory of the backup
+        backup: PathBuf,
+        /// The state hash of the root, required if the forks of the first level
+        /// share the ledger of the backup
+        #[structopt(long)]
+        root: Option<String>,
+    },
+    /// Check the consistency of the database and exit
+    Fsck {
+        /// Fix the indexes and move the blocks stored under a wrong hash
+        #[structopt(long)]
+        repair: bool,
+    },
+    /// Create a snapshot of the database in `--snapshot-dir` and exit,
+    /// use the `/snapshot` endpoint while the archive is running
+    Snapshot,
 }
 
 #[tokio::main]
@@ -42,36 +109,129 @@ async fn main() {
 
     let Args {
         path,
+        network: profile,
         chain_id,
         listen,
         peer,
         http,
+        migrate_dry_run,
+        genesis,
+        genesis_winner,
+        snapshot_dir,
+        snapshot_keep,
+        secondary,
+        catch_up_interval,
+        command,
     } = Args::from_args();
 
-    let default_peers = [
-        "/ip4/65.21.123.88/tcp/8302/p2p/12D3KooWLKSM9oHWU7qwL7Ci75wunkjXpRmK6j5xq527zGw554AF",
-        "/ip4/65.109.123.166/tcp/8302/p2p/12D3KooWGc9vwL9DUvoLdBFPSQGCT2QTULskzhmXcn8zg2j3jdFF",
-        "/ip4/176.9.64.21/tcp/8302/p2p/12D3KooWG9owTshte2gR3joP4sgwAfdoV9bQeeB5y9R3QUprKLdJ",
-        "/ip4/35.238.71.15/tcp/65454/p2p/12D3KooWHdUVpCZ9KcF5hNBrwf2uy7BaPDKrxyHJAaM5epJgQucX",
-        "/ip4/35.224.199.118/tcp/25493/p2p/12D3KooWGbjV7ptpzLu4BuykKfEsF4ebLyR8gZAMUissMToKGVDQ",
-        "/ip4/35.193.28.252/tcp/37470/p2p/12D3KooWFcCiQqrzBVLEkPdpkHDgWr6AkSMthT96agKYBBVuRhHg",
-        "/ip4/142.132.154.120/tcp/58654/p2p/12D3KooWMPxTu24mCpi3TwmkU4fJk7a8TQ4agFZeTHQRi8KCc3nj",
-        "/ip4/65.108.121.245/tcp/8302/p2p/12D3KooWGQ4g2eY44n5JLqymi8KC55GbnujAFeXNQrmNKSq4NYrv",
-        "/ip4/65.109.123.173/tcp/8302/p2p/12D3KooWMd8K8FFd76cacUEE6sSzUPr7wj71TvMqGdFSgrpv923k",
-        "/ip4/65.109.123.235/tcp/8302/p2p/12D3KooWBK3vz1inMubXCUeDF4Min6eG5418toceG8QvNPWRW1Gz",
-        "/ip4/34.172.208.246/tcp/46203/p2p/12D3KooWNafCBobFGSdJyYonvSCB5KDzW3JZYnVBF6q22yhcXGjM",
-        "/ip4/34.29.40.184/tcp/7528/p2p/12D3KooWJoVjUsnDosW3Ae78V4CSf5SSe9Wyetr5DxutmMMfwdp8",
-        "/ip4/34.122.249.235/tcp/55894/p2p/12D3KooWMpGyhYHbzVeqYnxGHQQYmQNtYcoMLLZZmYRPvAJKxXXm",
-        "/ip4/35.232.20.138/tcp/10000/p2p/12D3KooWAdgYL6hv18M3iDBdaK1dRygPivSfAfBNDzie6YqydVbs",
-        "/ip4/88.198.230.168/tcp/8302/p2p/12D3KooWGA7AS91AWNtGEBCBk64kgirtTiyaXDTyDtKPTjpefNL9",
-        "/ip4/35.224.199.118/tcp/10360/p2p/12D3KooWDnC4XrJzas3heuz4LUehZjf2WJyfob2XEodrYL3soaf4",
-        "/ip4/34.123.4.144/tcp/10002/p2p/12D3KooWEiGVAFC7curXWXiGZyMWnZK9h8BKr88U8D5PKV3dXciv",
-        "/ip4/34.170.114.52/tcp/10001/p2p/12D3KooWLjs54xHzVmMmGYb7W5RVibqbwD1co7M2ZMfPgPm7iAag",
-        "/ip4/34.172.208.246/tcp/54351/p2p/12D3KooWEhCm8FVcqZSkXKNhuBPmsEfJGeqSmUxNQhpemZkENfik",
-        "/ip4/34.29.161.11/tcp/10946/p2p/12D3KooWCntSrMqSiovXcVfMZ56aYbzpZoh4mi7gJJNiZBmzXrpa",
-        "/ip4/35.238.71.15/tcp/23676/p2p/12D3KooWENsfMszNYBRfHZJUEAvXKThmZU3nijWVbLivq33AE2Vk",
-    ]
-    .map(|s| s.parse().unwrap());
+    if migrate_dry_run {
+        let db = db::Db::open_read_only(path).unwrap();
+        let version = db.schema_version().unwrap();
+        log::info!("schema version {version:?}, current {}", db::SCHEMA_VERSION);
+        for report in db.migrate(true).unwrap() {
+            log::info!("{report}");
+        }
+        return;
+    }
+
+    match command {
+        Some(Command::VerifyProofs { redo }) => {
+            let db = db::Db::open(path).unwrap();
+            let (valid, invalid) = db.verify_proofs(redo).unwrap();
+            log::info!("verified proofs, valid: {valid}, invalid: {invalid}");
+            return;
+        }
+        Some(Command::Import { backup, root }) => {
+            let root = match root
+                .map(|hash| serde_json::from_str(&format!("\"{hash}\"")))
+                .transpose()
+            {
+                Ok(root) => root,
+                Err(err) => {
+                    log::error!("bad root hash: {err}");
+                    return;
+                }
+            };
+            let db = db::Db::open(path).unwrap();
+            if let Err(err) = import::run(&db, &backup, profile.ledger_depth, root) {
+                log::error!("cannot import the backup: {err}");
+            }
+            return;
+        }
+        Some(Command::Fsck { repair }) => {
+            let db = db::Db::open(path).unwrap();
+            let constants = profile.constraint_constants();
+            match db.fsck(repair, profile.ledger_depth, &constants) {
+                Ok(report) if report.is_ok() => log::info!("{report}"),
+                Ok(report) => {
+                    log::warn!("{report}");
+                    std::process::exit(1);
+                }
+                Err(err) => log::error!("fsck: {err}"),
+            }
+            return;
+        }
+        Some(Command::Snapshot) => {
+            let Some(dir) = snapshot_dir else {
+                log::error!("--snapshot-dir is required");
+                return;
+            };
+            let snapshots = snapshot::Snapshots {
+                db: Arc::new(db::Db::open(path).unwrap()),
+                dir,
+                keep: snapshot_keep,
+            };
+            if let Err(err) = snapshots.create() {
+                log::error!("cannot create the snapshot: {err}");
+            }
+            return;
+        }
+        None => {}
+    }
+
+    if let Some(secondary) = secondary {
+        let db = Arc::new(db::Db::open_secondary(path, secondary).unwrap());
+        // the receiver is dropped, the server refuses to append
+        let (tx, _) = mpsc::unbounded_channel();
+        server::spawn(db.clone(), http.expect("required"), tx, None);
+        let mut interval = time::interval(Duration::from_secs(catch_up_interval));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    if let Err(err) = db.catch_up() {
+                        log::error!("cannot catch up with the primary: {err}");
+                    }
+                }
+                _ = signal::ctrl_c() => break,
+            }
+        }
+        return;
+    }
+
+    let Some(chain_id) = chain_id.or_else(|| profile.chain_id.clone()) else {
+        log::error!(
+            "--chain-id is required, the network profile {} has none",
+            profile.name
+        );
+        return;
+    };
+
+    let genesis_winner = genesis_winner.unwrap_or_else(|| profile.genesis_winner.clone());
+    let genesis = match genesis.map(|path| genesis::load(path, &genesis_winner)) {
+        None => None,
+        Some(Ok(accounts)) => Some(accounts),
+        Some(Err(err)) => {
+            log::error!("cannot load the genesis ledger: {err}");
+            return;
+        }
+    };
+
+    let seed_peers = profile
+        .seed_peers
+        .iter()
+        .map(|s| s.parse::<Multiaddr>())
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
 
     let sk = env::var("OPENMINA_P2P_SEC_KEY")
         .map(|key| {
@@ -93,16 +253,23 @@ async fn main() {
         local_key.clone(),
         chain_id.as_bytes(),
         listen,
-        peer.into_iter().chain(default_peers),
-        main_loop::B::new(local_key),
+        peer.into_iter().chain(seed_peers),
+        main_loop::B::new(local_key, &profile.gossip_topic),
     );
 
     let (tx, rx) = mpsc::unbounded_channel();
     let db = Arc::new(db::Db::open(path).unwrap());
     if let Some(port) = http {
-        server::spawn(db.clone(), port, tx);
+        let snapshots = snapshot_dir.map(|dir| {
+            Arc::new(snapshot::Snapshots {
+                db: db.clone(),
+                dir,
+                keep: snapshot_keep,
+            })
+        });
+        server::spawn(db.clone(), port, tx, snapshots);
     }
-    if let Err(err) = main_loop::run(swarm, db, rx).await {
+    if let Err(err) = main_loop::run(swarm, db, rx, genesis, profile).await {
         log::error!("fatal: {err}");
     }
 }
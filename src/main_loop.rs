@@ -1,4 +1,8 @@
-use std::{sync::Arc, ops::DerefMut};
+use std::{
+    sync::Arc,
+    ops::DerefMut,
+    collections::{BTreeMap, BTreeSet},
+};
 
 use libp2p::{
     swarm::{NetworkBehaviour, SwarmEvent, THandlerErr},
@@ -6,19 +10,48 @@ use libp2p::{
     identity::Keypair,
     futures::{Stream, StreamExt},
 };
+use thiserror::Error;
 use tokio::{sync::mpsc, signal};
 use vru_cancel::{Canceler, cancelable};
 
 use libp2p_rpc_behaviour::BehaviourBuilder;
 use mina_p2p_messages::v2;
-use mina_tree::BaseLedger;
+use mina_tree::scan_state::scan_state::ConstraintConstants;
+use openmina_archive_profile::NetworkProfile;
 
 use super::{
-    client::Client,
-    db::{Db, DbError, BlockHeader},
-    snarked_ledger::SnarkedLedger,
+    client::{Client, ClientError, TSwarmEvent, TSwarm},
+    db::{self, DbError, BlockHeader, Storage},
+    snarked_ledger::{self, SnarkedLedger},
+    replay::{self, Replayer},
+    validation::{self, ValidationError},
 };
 
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{0}")]
+    Db(#[from] DbError),
+    #[error("{0}")]
+    Client(#[from] ClientError),
+    #[error("ledger sync {0}")]
+    LedgerSync(#[from] snarked_ledger::Error),
+    #[error("peer has no best tip")]
+    NoBestTip,
+    #[error("peer has no block {0}")]
+    NoBlock(v2::StateHash),
+    #[error("invalid block {0}")]
+    Invalid(#[from] ValidationError),
+    #[error("genesis ledger hash mismatch, expected {expected}, actual {actual}")]
+    GenesisLedgerMismatch {
+        expected: v2::LedgerHash,
+        actual: v2::LedgerHash,
+    },
+    #[error("staged ledger hash of the genesis block does not match")]
+    GenesisStagedLedgerMismatch,
+    #[error("{0}")]
+    Replay(#[from] replay::Error),
+}
+
 #[derive(NetworkBehaviour)]
 pub struct B {
     pub rpc: libp2p_rpc_behaviour::Behaviour,
@@ -26,7 +59,7 @@ pub struct B {
 }
 
 impl B {
-    pub fn new(local_key: Keypair) -> Self {
+    pub fn new(local_key: Keypair, topic: &str) -> Self {
         use mina_p2p_messages::rpc::*;
 
         let gossip = {
@@ -40,7 +73,7 @@ impl B {
                 gossipsub::subscription_filter::AllowAllSubscriptionFilter,
             >::new(message_authenticity, gossipsub_config)
             .expect("strict validation mode must be compatible with this `message_authenticity`");
-            let topic = gossipsub::IdentTopic::new("coda/consensus-messages/0.0.1");
+            let topic = gossipsub::IdentTopic::new(topic);
             behaviour.subscribe(&topic).unwrap();
             behaviour
         };
@@ -58,107 +91,507 @@ impl B {
     }
 }
 
-pub async fn bootstrap(
+pub async fn bootstrap<D>(
     swarm: impl Unpin
         + Send
         + Stream<Item = SwarmEvent<BEvent, THandlerErr<B>>>
         + DerefMut<Target = Swarm<B>>,
-    db: Arc<Db>,
+    db: Arc<D>,
     mut crx: mpsc::UnboundedReceiver<v2::StateHash>,
-) -> Result<(), DbError> {
+    genesis: Option<Vec<v2::MinaBaseAccountBinableArgStableV2>>,
+    profile: NetworkProfile,
+) -> Result<(), Error>
+where
+    D: Storage,
+{
+    let mut client = Client::new(swarm, db.clone(), profile.ledger_depth);
+    let shared_db = db;
+    let db = &*shared_db;
+
+    if db.root().is_err() {
+        match genesis {
+            Some(accounts) => {
+                let constants = profile.constraint_constants();
+                bootstrap_from_genesis(&mut client, db, accounts, &constants).await?
+            }
+            None => bootstrap_from_root(&mut client, db).await?,
+        }
+    }
+
+    let replay = Replayer::spawn(shared_db.clone(), profile.constraint_constants());
+
+    let missing_heights = db.missing_heights()?;
+    if !missing_heights.is_empty() {
+        log::info!("missing heights: {missing_heights:?}");
+    }
+    // the missing parents are the hashes of the top of each missing range of heights,
+    // and of the forks whose parent is missing
+    backfill(&mut client, db, db.missing_parents()?).await?;
+    sync_canonical_ledger_diffs(&mut client, db).await?;
+    // the replayer is gone if it failed to start, nothing to tell it then
+    let _ = replay.send(());
+
+    loop {
+        for (hash, block) in client.take_new_blocks() {
+            if let Err(err) = on_new_block(&mut client, db, hash.clone(), block).await {
+                log::error!("block {hash}: {err}");
+            }
+            let _ = replay.send(());
+        }
+
+        tokio::select! {
+            event = client.swarm.next() => {
+                if let Some(event) = event {
+                    if let Some((hash, block)) = client.process(event) {
+                        if let Err(err) = on_new_block(&mut client, db, hash.clone(), block).await {
+                            log::error!("block {hash}: {err}");
+                        }
+                        let _ = replay.send(());
+                    }
+                } else {
+                    break;
+                }
+            }
+            command = crx.recv() => {
+                if let Some(hash) = command {
+                    if let Err(err) = append(&mut client, db, &hash).await {
+                        log::error!("append {hash}: {err}");
+                    }
+                    let _ = replay.send(());
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Roots the archive at the root of the peer's transition frontier,
+/// syncs the snarked ledger and the staged ledger aux of the root.
+async fn bootstrap_from_root<S, D>(client: &mut Client<S, D>, db: &D) -> Result<(), Error>
+where
+    S: Unpin + Send + Stream<Item = TSwarmEvent> + DerefMut<Target = TSwarm>,
+    D: Storage,
+{
     use mina_p2p_messages::rpc;
 
-    let mut client = Client::new(swarm, db.clone());
+    let best_tip = client
+        .rpc::<rpc::GetBestTipV2>(())
+        .await?
+        .ok_or(Error::NoBestTip)?;
 
-    if db.root().is_err() {
-        let best_tip = client.rpc::<rpc::GetBestTipV2>(()).await.unwrap().unwrap();
+    log::info!("best tip {}", best_tip.data.height());
+
+    let hash = validation::check(&best_tip.proof.1, None)?;
+    let root = best_tip.proof.1.height();
 
-        log::info!("best tip {}", best_tip.data.height());
+    db.put_block(hash.clone(), best_tip.proof.1.clone())?;
 
-        let hash = best_tip.proof.1.hash();
-        let root = best_tip.proof.1.height();
+    let ledger_hash = best_tip.proof.1.snarked_ledger_hash();
+    log::info!("syncing {ledger_hash}...");
 
-        db.put_block(hash.clone(), best_tip.proof.1.clone())?;
+    let mut ledger = SnarkedLedger::empty(client.ledger_depth());
+    ledger.sync_new(client, &ledger_hash, Some(db)).await?;
 
-        let ledger_hash = best_tip.proof.1.snarked_ledger_hash();
-        log::info!("syncing {ledger_hash}...");
+    log::info!("sync done {ledger_hash}");
 
-        let mut ledger = SnarkedLedger::empty();
-        ledger.sync_new(&mut client, &ledger_hash).await;
+    db.put_ledger(ledger_hash, ledger.accounts())?;
+    db.clear_ledger_sync()?;
 
-        log::info!("sync done {ledger_hash}");
+    let aux = client
+        .rpc::<rpc::GetStagedLedgerAuxAndPendingCoinbasesAtHashV2>(hash.clone().into_inner().0)
+        .await?;
+    db.put_aux(hash.clone(), aux)?;
 
-        let mut accounts = vec![];
-        ledger.inner.iter(|account| accounts.push(account.into()));
-        db.put_ledger(ledger_hash, accounts)?;
+    log::info!("aux done {hash}");
+
+    let mut block = best_tip.data;
+    let mut head = validation::check(&block, None)?;
+    let mut chain = vec![head.clone()];
+    db.put_block(head.clone(), block.clone())?;
+    while head != hash {
+        let prev = block.header.protocol_state.previous_state_hash.clone();
+        let parent = fetch_block(client, db, &prev).await?;
+        validation::check_with_parent(&block, &parent)?;
+        block = parent;
+        head = prev;
+        db.put_block(head.clone(), block.clone())?;
+        chain.push(head.clone());
+    }
 
-        let aux = client
-            .rpc::<rpc::GetStagedLedgerAuxAndPendingCoinbasesAtHashV2>(hash.clone().into_inner().0)
-            .await
-            .unwrap();
-        db.put_aux(hash.clone(), aux)?;
+    db.put_root(root)?;
+
+    // the root is the last one, its ledger is already stored
+    for hash in chain.into_iter().rev().skip(1) {
+        sync_ledger_diff(client, db, &hash).await?;
+    }
+
+    Ok(())
+}
+
+/// Roots the archive at the genesis block, its snarked ledger is the genesis ledger
+/// and its scan state is empty. Peers must still have all blocks from the genesis.
+async fn bootstrap_from_genesis<S, D>(
+    client: &mut Client<S, D>,
+    db: &D,
+    accounts: Vec<v2::MinaBaseAccountBinableArgStableV2>,
+    constants: &ConstraintConstants,
+) -> Result<(), Error>
+where
+    S: Unpin + Send + Stream<Item = TSwarmEvent> + DerefMut<Target = TSwarm>,
+    D: Storage,
+{
+    use mina_p2p_messages::rpc;
 
-        log::info!("aux done {hash}");
+    let best_tip = client
+        .rpc::<rpc::GetBestTipV2>(())
+        .await?
+        .ok_or(Error::NoBestTip)?;
 
-        let mut block = best_tip.data;
-        let mut head = block.hash();
-        while head != hash {
-            let prev = block.header.protocol_state.previous_state_hash;
-            block = client
-                .rpc::<rpc::GetTransitionChainV2>(vec![prev.clone().into_inner().0])
-                .await
-                .unwrap()
-                .unwrap()
-                .first()
-                .unwrap()
-                .clone();
-            head = prev;
-            db.put_block(head.clone(), block.clone())?;
+    log::info!("best tip {}", best_tip.data.height());
+
+    let mut block = best_tip.data;
+    let mut head = validation::check(&block, None)?;
+    let mut chain = vec![head.clone()];
+    db.put_block(head.clone(), block.clone())?;
+    while block.height() > 1 {
+        let prev = block.header.protocol_state.previous_state_hash.clone();
+        let parent = fetch_block(client, db, &prev).await?;
+        validation::check_with_parent(&block, &parent)?;
+        block = parent;
+        head = prev;
+        db.put_block(head.clone(), block.clone())?;
+        chain.push(head.clone());
+    }
+
+    let expected = block.snarked_ledger_hash();
+    let ledger_hash = SnarkedLedger::from_accounts(client.ledger_depth(), &accounts).merkle_root();
+    if expected != ledger_hash {
+        return Err(Error::GenesisLedgerMismatch {
+            expected,
+            actual: ledger_hash,
+        });
+    }
+    log::info!("genesis ledger {ledger_hash}, {} accounts", accounts.len());
+
+    // the staged ledger of the genesis block is created here rather than fetched from the peer
+    let expected = &block
+        .header
+        .protocol_state
+        .body
+        .blockchain_state
+        .staged_ledger_hash;
+    let staged_ledger_hash = replay::genesis_staged_ledger_hash(constants, accounts.clone())?;
+    if *expected != staged_ledger_hash {
+        return Err(Error::GenesisStagedLedgerMismatch);
+    }
+
+    db.put_ledger(ledger_hash, accounts)?;
+    db.put_aux(head, None)?;
+    db.put_root(1)?;
+
+    // the genesis block is the last one, its ledger is already stored
+    for hash in chain.into_iter().rev().skip(1) {
+        sync_ledger_diff(client, db, &hash).await?;
+    }
+
+    Ok(())
+}
+
+fn quarantine<D>(
+    db: &D,
+    hash: v2::StateHash,
+    block: v2::MinaBlockBlockStableV2,
+    err: &ValidationError,
+) -> Result<(), Error>
+where
+    D: Storage,
+{
+    log::warn!("quarantine {} {hash}: {err}", block.height());
+    db.put_quarantined(hash, err.to_string(), block)
+        .map_err(Into::into)
+}
+
+/// Fetches the block by hash, the block is quarantined if its hash does not match.
+async fn fetch_block<S, D>(
+    client: &mut Client<S, D>,
+    db: &D,
+    hash: &v2::StateHash,
+) -> Result<v2::MinaBlockBlockStableV2, Error>
+where
+    S: Unpin + Send + Stream<Item = TSwarmEvent> + DerefMut<Target = TSwarm>,
+    D: Storage,
+{
+    use mina_p2p_messages::rpc;
+
+    let block = client
+        .rpc::<rpc::GetTransitionChainV2>(vec![hash.clone().into_inner().0])
+        .await?
+        .and_then(|blocks| blocks.into_iter().next())
+        .ok_or_else(|| Error::NoBlock(hash.clone()))?;
+    if let Err(err) = validation::check(&block, Some(hash)) {
+        quarantine(db, block.hash(), block, &err)?;
+        return Err(err.into());
+    }
+
+    Ok(block)
+}
+
+async fn append<S, D>(client: &mut Client<S, D>, db: &D, hash: &v2::StateHash) -> Result<(), Error>
+where
+    S: Unpin + Send + Stream<Item = TSwarmEvent> + DerefMut<Target = TSwarm>,
+    D: Storage,
+{
+    log::info!("fetching {hash}");
+    let block = fetch_block(client, db, hash).await?;
+    log::info!("adding {hash}");
+    on_new_block(client, db, hash.clone(), block).await
+}
+
+/// Validates the new block against its parent, fetching the missing ancestors first,
+/// then stores it and syncs its ledger diff. The invalid block goes to quarantine.
+async fn on_new_block<S, D>(
+    client: &mut Client<S, D>,
+    db: &D,
+    hash: v2::StateHash,
+    block: v2::MinaBlockBlockStableV2,
+) -> Result<(), Error>
+where
+    S: Unpin + Send + Stream<Item = TSwarmEvent> + DerefMut<Target = TSwarm>,
+    D: Storage,
+{
+    if db.contains_block(&hash)? {
+        return Ok(());
+    }
+    if let Err(err) = validation::check(&block, Some(&hash)) {
+        return quarantine(db, hash, block, &err);
+    }
+
+    let root = db.root()?;
+    if block.height() <= root {
+        // the parent of such a block is not stored, so it is only taken
+        // as the ancestor of a stored block, the hash links them
+        if db.children(&hash)?.is_empty() {
+            let err = ValidationError::BelowRoot {
+                root,
+                actual: block.height(),
+            };
+            return quarantine(db, hash, block, &err);
+        }
+    } else {
+        let parent_hash = block.header.protocol_state.previous_state_hash.clone();
+        if !db.contains_block(&parent_hash)? {
+            backfill(client, db, vec![(block.height() - 1, parent_hash.clone())]).await?;
+        }
+        let result = match db.block_full(&parent_hash) {
+            Ok(parent) => validation::check_with_parent(&block, &parent),
+            Err(DbError::BlockNotFound(_)) => Err(ValidationError::ParentUnknown(parent_hash)),
+            Err(err) => return Err(err.into()),
+        };
+        if let Err(err) = result {
+            return quarantine(db, hash, block, &err);
         }
+    }
+
+    db.put_block(hash.clone(), block)?;
+    sync_ledger_diff(client, db, &hash).await
+}
 
-        db.put_root(root)?;
+/// Fetches the `missing` blocks and their missing ancestors, walking back from each gap,
+/// until the chain is connected to the root or peers cannot provide the missing blocks.
+/// The next missing blocks of the gaps are fetched together in batches.
+/// A failed request stops the backfill, it is not an error.
+async fn backfill<S, D>(
+    client: &mut Client<S, D>,
+    db: &D,
+    missing: Vec<(u32, v2::StateHash)>,
+) -> Result<(), Error>
+where
+    S: Unpin + Send + Stream<Item = TSwarmEvent> + DerefMut<Target = TSwarm>,
+    D: Storage,
+{
+    use mina_p2p_messages::rpc;
+
+    const BATCH: usize = 16;
+
+    let mut missing = missing
+        .into_iter()
+        .map(|(height, hash)| (hash, height))
+        .collect::<BTreeMap<_, _>>();
+    if missing.is_empty() {
+        return Ok(());
     }
 
+    let root = db.root()?;
+    let mut failed = BTreeSet::new();
+    let mut fetched = vec![];
     loop {
-        tokio::select! {
-            event = client.swarm.next() => {
-                if let Some(event) = event {
-                    client.process(event);
-                } else {
-                    break;
+        let targets = missing
+            .keys()
+            .filter(|hash| !failed.contains(*hash))
+            .take(BATCH)
+            .cloned()
+            .collect::<Vec<_>>();
+        if targets.is_empty() {
+            break;
+        }
+        log::info!("backfill {} blocks", targets.len());
+
+        // the peer answers `None` if any of the blocks is unknown,
+        // so if the batch fails, retry each target separately
+        let request = targets
+            .iter()
+            .map(|hash| hash.clone().into_inner().0)
+            .collect();
+        let batches = match client.rpc::<rpc::GetTransitionChainV2>(request).await {
+            Ok(Some(blocks)) => vec![blocks],
+            Ok(None) if targets.len() > 1 => {
+                let mut batches = vec![];
+                for hash in &targets {
+                    let query = vec![hash.clone().into_inner().0];
+                    match client.rpc::<rpc::GetTransitionChainV2>(query).await {
+                        Ok(response) => batches.extend(response),
+                        Err(err) => log::warn!("cannot backfill {hash}: {err}"),
+                    }
                 }
+                batches
             }
-            command = crx.recv() => {
-                if let Some(hash) = command {
-                    log::info!("fetching {hash}");
-                    let block = client.rpc::<rpc::GetTransitionChainV2>(vec![hash.clone().into_inner().0])
-                        .await
-                        .unwrap()
-                        .unwrap()
-                        .first()
-                        .unwrap()
-                        .clone();
-                    log::info!("adding {hash}");
-                    db.put_block(hash, block).unwrap();
+            Ok(None) => vec![],
+            Err(err) => {
+                log::warn!("cannot backfill: {err}");
+                break;
+            }
+        };
+
+        let mut received = BTreeMap::new();
+        for block in batches.into_iter().flatten() {
+            let result = validation::check(&block, None).and_then(|hash| {
+                if targets.contains(&hash) {
+                    Ok(hash)
+                } else {
+                    Err(ValidationError::NotRequested)
+                }
+            });
+            match result {
+                Ok(hash) => {
+                    received.insert(hash, block);
                 }
+                Err(err) => quarantine(db, block.hash(), block, &err)?,
+            }
+        }
+
+        // a block is stored only once a stored block refers to it as the parent,
+        // then its own parent is the next missing one
+        while let Some(hash) = received.keys().find(|h| missing.contains_key(*h)).cloned() {
+            let block = received.remove(&hash).expect("just found");
+            let height = block.height();
+            let parent = block.header.protocol_state.previous_state_hash.clone();
+            log::info!("backfill {height} {hash}");
+            db.put_block(hash.clone(), block)?;
+            missing.remove(&hash);
+            if height > root && !db.contains_block(&parent)? {
+                missing.insert(parent, height - 1);
+            }
+            fetched.push((height, hash));
+        }
+        for hash in targets {
+            if let Some(height) = missing.get(&hash) {
+                log::warn!("cannot backfill {height} {hash}");
+                failed.insert(hash);
             }
         }
     }
 
+    fetched.sort();
+    for (_, hash) in fetched {
+        sync_ledger_diff(client, db, &hash).await?;
+    }
+
+    Ok(())
+}
+
+/// Sync the missing ledger diffs of the canonical chain,
+/// the blocks restored by `import` come without them.
+async fn sync_canonical_ledger_diffs<S, D>(client: &mut Client<S, D>, db: &D) -> Result<(), Error>
+where
+    S: Unpin + Send + Stream<Item = TSwarmEvent> + DerefMut<Target = TSwarm>,
+    D: Storage,
+{
+    let root = db.root()?;
+    let canonical = db
+        .block(db::BlockId::Canonical(root + 1))
+        .map(|r| r.map(|(_, hashes)| hashes))
+        .collect::<Result<Vec<_>, _>>()?;
+    for hash in canonical.into_iter().flatten() {
+        sync_ledger_diff(client, db, &hash).await?;
+    }
+
     Ok(())
 }
 
-pub async fn run(
+/// Sync the snarked ledger of the block if it differs from the parent's one,
+/// and store the difference.
+async fn sync_ledger_diff<S, D>(
+    client: &mut Client<S, D>,
+    db: &D,
+    hash: &v2::StateHash,
+) -> Result<(), Error>
+where
+    S: Unpin + Send + Stream<Item = TSwarmEvent> + DerefMut<Target = TSwarm>,
+    D: Storage,
+{
+    if db.ledger_diff(hash).is_ok() {
+        return Ok(());
+    }
+
+    let block = db.block_full(hash)?;
+    let prev_hash = block.header.protocol_state.previous_state_hash.clone();
+    let Ok(prev) = db.block_full(&prev_hash) else {
+        log::warn!("cannot sync ledger diff of {hash}, parent {prev_hash} is unknown");
+        return Ok(());
+    };
+    let ledger_hash = block.snarked_ledger_hash();
+    if ledger_hash == prev.snarked_ledger_hash() {
+        return Ok(());
+    }
+
+    let accounts = match db.ledger_at(&prev_hash) {
+        Ok(v) => v,
+        Err(err) => {
+            log::warn!("cannot sync ledger diff of {hash}, {err}");
+            return Ok(());
+        }
+    };
+
+    log::info!("syncing ledger diff {ledger_hash}...");
+    let mut ledger = SnarkedLedger::from_accounts(client.ledger_depth(), &accounts);
+    // the peers serve only the recent ledgers, the older diffs of an imported backup are skipped
+    if let Err(err) = ledger.sync_new(client, &ledger_hash, None).await {
+        log::warn!("cannot sync ledger diff of {hash}, {err}");
+        return Ok(());
+    }
+    let diff = db::diff_ledgers(&accounts, &ledger.accounts());
+    log::info!("ledger diff {hash}: {} accounts changed", diff.len());
+
+    db.put_ledger_diff(hash.clone(), diff).map_err(Into::into)
+}
+
+pub async fn run<D>(
     swarm: Swarm<B>,
-    db: Arc<Db>,
+    db: Arc<D>,
     crx: mpsc::UnboundedReceiver<v2::StateHash>,
-) -> Result<(), DbError> {
+    genesis: Option<Vec<v2::MinaBaseAccountBinableArgStableV2>>,
+    profile: NetworkProfile,
+) -> Result<(), Error>
+where
+    D: Storage,
+{
     let trigger = Canceler::spawn({
         let db = db.clone();
         move |canceler| {
             tokio::spawn(async move {
                 cancelable!(swarm, canceler);
-                bootstrap(swarm, db.clone(), crx).await
+                bootstrap(swarm, db.clone(), crx, genesis, profile).await
             })
         }
     });
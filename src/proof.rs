@@ -0,0 +1,69 @@
+use mina_p2p_messages::v2;
+
+/// The result of the verification of the protocol state proof of a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProofStatus {
+    Valid,
+    Invalid,
+}
+
+impl ProofStatus {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            ProofStatus::Valid => 1,
+            ProofStatus::Invalid => 0,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(ProofStatus::Valid),
+            0 => Some(ProofStatus::Invalid),
+            _ => None,
+        }
+    }
+}
+
+/// Verifies the blockchain SNARK of the block, the verification key is embedded in `mina-tree`.
+/// The verifier is the one of the `mina-tree` revision pinned in the workspace manifest,
+/// `network_proof` checks it against a block of the network.
+pub fn verify(block: &v2::MinaBlockBlockStableV2) -> ProofStatus {
+    if mina_tree::proofs::verification::verify_block(&block.header) {
+        ProofStatus::Valid
+    } else {
+        ProofStatus::Invalid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, io::BufReader, path::PathBuf};
+
+    use mina_p2p_messages::{binprot::BinProtRead, v2};
+
+    use super::{verify, ProofStatus};
+    use crate::testing::field;
+
+    #[test]
+    fn byte_roundtrip() {
+        for status in [ProofStatus::Valid, ProofStatus::Invalid] {
+            assert_eq!(ProofStatus::from_byte(status.to_byte()), Some(status));
+        }
+        assert_eq!(ProofStatus::from_byte(2), None);
+    }
+
+    #[test]
+    #[ignore = "needs fixtures/gossip_block, a block of the network in binprot"]
+    fn network_proof() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join("gossip_block");
+        let mut file = BufReader::new(File::open(path).unwrap());
+        let mut block = v2::MinaBlockBlockStableV2::binprot_read(&mut file).unwrap();
+        assert_eq!(verify(&block), ProofStatus::Valid);
+
+        // the proof is of the protocol state, it does not hold for another one
+        block.header.protocol_state.previous_state_hash = field(0);
+        assert_eq!(verify(&block), ProofStatus::Invalid);
+    }
+}
@@ -0,0 +1,322 @@
+use std::{collections::BTreeMap, sync::Arc, thread};
+
+use mina_p2p_messages::v2;
+use mina_signer::CompressedPubKey;
+use mina_tree::{
+    mask::Mask,
+    staged_ledger::{staged_ledger::StagedLedger, diff::Diff},
+    verifier::Verifier,
+    scan_state::{
+        scan_state::{ConstraintConstants, ScanState},
+        transaction_logic::{local_state::LocalState, protocol_state},
+        self,
+    },
+    Database, BaseLedger,
+};
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use tokio::sync::mpsc;
+
+use super::db::{DbError, BlockId, BlockHeader, Storage};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{0}")]
+    Db(#[from] DbError),
+    #[error("root block is unknown")]
+    NoRoot,
+    #[error("root block {0} has no staged ledger aux info")]
+    EmptyAux(v2::StateHash),
+    #[error("cannot construct the staged ledger of the root: {0}")]
+    StagedLedger(String),
+    #[error("aux of the root {0} lacks a protocol state the scan state refers to")]
+    MissingProtocolState(v2::StateHash),
+    #[error("staged ledger hash of the root {0} does not match")]
+    RootMismatch(v2::StateHash),
+}
+
+/// Constructs the staged ledger of the root and compares its hash with the root block's one.
+pub fn check_root<D>(db: &D, constants: &ConstraintConstants) -> Result<(), Error>
+where
+    D: Storage,
+{
+    State::root(db, constants).map(drop)
+}
+
+/// Creates the staged ledger of the genesis block from the genesis ledger, returns its hash.
+pub fn genesis_staged_ledger_hash(
+    constants: &ConstraintConstants,
+    accounts: Vec<v2::MinaBaseAccountBinableArgStableV2>,
+) -> Result<v2::MinaBaseStagedLedgerHashStableV1, Error> {
+    let snarked_ledger = snarked_ledger(constants, accounts)?;
+    let staged_ledger =
+        StagedLedger::create_exn(constants.clone(), snarked_ledger).map_err(Error::StagedLedger)?;
+    Ok(v2::MinaBaseStagedLedgerHashStableV1::from(
+        &staged_ledger.hash(),
+    ))
+}
+
+fn snarked_ledger(
+    constants: &ConstraintConstants,
+    accounts: Vec<v2::MinaBaseAccountBinableArgStableV2>,
+) -> Result<Mask, Error> {
+    let mut snarked_ledger = Mask::new_root(Database::create(constants.ledger_depth as _));
+    for account in accounts {
+        let account = mina_tree::Account::from(&account);
+        let account_id = account.id();
+        snarked_ledger
+            .get_or_create_account(account_id, account)
+            .map_err(|err| Error::StagedLedger(format!("{err:?}")))?;
+    }
+    let _ = snarked_ledger.merkle_root();
+
+    Ok(snarked_ledger)
+}
+
+/// The outcome of applying the block to the staged ledger of its parent.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ReplayResult {
+    Ok,
+    HashMismatch {
+        expected: v2::MinaBaseStagedLedgerHashStableV1,
+        actual: v2::MinaBaseStagedLedgerHashStableV1,
+    },
+    Error {
+        message: String,
+    },
+}
+
+struct State {
+    height: u32,
+    protocol_state: v2::MinaStateProtocolStateValueStableV2,
+    staged_ledger: StagedLedger,
+}
+
+impl State {
+    fn root<D>(db: &D, constants: &ConstraintConstants) -> Result<(v2::StateHash, Self), Error>
+    where
+        D: Storage,
+    {
+        let root = db.root()?;
+        let hash = db.canonical(root)?.ok_or(Error::NoRoot)?;
+        let block = db.block_full(&hash)?;
+        let accounts = db.ledger(&block.snarked_ledger_hash())?;
+        let aux = db.aux(&hash)?;
+
+        let snarked_ledger = snarked_ledger(constants, accounts)?;
+
+        let staged_ledger = match aux {
+            // the scan state of the genesis block is empty
+            None if root == 1 => StagedLedger::create_exn(constants.clone(), snarked_ledger)
+                .map_err(Error::StagedLedger)?,
+            None => return Err(Error::EmptyAux(hash)),
+            Some((scan_state, expected_ledger_hash, pending_coinbase, states)) => {
+                let states = states
+                    .into_iter()
+                    .filter_map(|state| Some((state.hash().to_fp().ok()?, state)))
+                    .collect::<BTreeMap<_, _>>();
+                let scan_state = ScanState::from(&scan_state);
+                // the staged ledger cannot report a missing state, so it is checked beforehand
+                let required = scan_state.required_state_hashes();
+                if !required.iter().all(|key| states.contains_key(key)) {
+                    return Err(Error::MissingProtocolState(hash));
+                }
+
+                StagedLedger::of_scan_state_pending_coinbases_and_snarked_ledger(
+                    (),
+                    constants,
+                    Verifier,
+                    scan_state,
+                    snarked_ledger,
+                    LocalState::empty(),
+                    expected_ledger_hash.into(),
+                    (&pending_coinbase).into(),
+                    |key| states.get(&key).cloned().expect("checked above"),
+                )
+                .map_err(|err| Error::StagedLedger(format!("{err:?}")))?
+            }
+        };
+
+        let protocol_state = block.header.protocol_state;
+        let actual = v2::MinaBaseStagedLedgerHashStableV1::from(&staged_ledger.hash());
+        if actual != protocol_state.body.blockchain_state.staged_ledger_hash {
+            return Err(Error::RootMismatch(hash));
+        }
+
+        let state = State {
+            height: root,
+            protocol_state,
+            staged_ledger,
+        };
+        Ok((hash, state))
+    }
+
+    /// Applies the block on top of this state. The new state is returned unless
+    /// the staged ledger failed to apply the block.
+    fn apply(
+        &self,
+        block: &v2::MinaBlockBlockStableV2,
+        constants: &ConstraintConstants,
+    ) -> (ReplayResult, Option<Self>) {
+        let mut staged_ledger = self.staged_ledger.clone();
+
+        let protocol_state = &block.header.protocol_state;
+        let consensus_state = &protocol_state.body.consensus_state;
+        let global_slot = &consensus_state.global_slot_since_genesis;
+        let prev_state_view = protocol_state::protocol_state_view(&self.protocol_state);
+        let coinbase_receiver: CompressedPubKey = (&consensus_state.coinbase_receiver).into();
+
+        // the coinbase is multiplied by `supercharged_coinbase_factor` of the constants
+        let supercharge_coinbase = consensus_state.supercharge_coinbase;
+
+        let diff: Diff = (&block.body.staged_ledger_diff).into();
+
+        let result = staged_ledger.apply(
+            None,
+            constants,
+            global_slot.into(),
+            diff,
+            (),
+            &Verifier,
+            &prev_state_view,
+            scan_state::protocol_state::hashes(&self.protocol_state),
+            coinbase_receiver,
+            supercharge_coinbase,
+        );
+        let result = match result {
+            Ok(v) => v,
+            Err(err) => {
+                let message = format!("{err:?}");
+                return (ReplayResult::Error { message }, None);
+            }
+        };
+
+        let actual = v2::MinaBaseStagedLedgerHashStableV1::from(&result.hash_after_applying);
+        let expected = protocol_state
+            .body
+            .blockchain_state
+            .staged_ledger_hash
+            .clone();
+        let result = if actual == expected {
+            ReplayResult::Ok
+        } else {
+            ReplayResult::HashMismatch { expected, actual }
+        };
+        let state = State {
+            height: block.height(),
+            protocol_state: protocol_state.clone(),
+            staged_ledger,
+        };
+        (result, Some(state))
+    }
+}
+
+/// Keeps the staged ledgers of the recent blocks, so every block that extends
+/// any live fork can be applied as it arrives.
+pub struct Replayer {
+    constants: ConstraintConstants,
+    states: BTreeMap<v2::StateHash, State>,
+    // blocks that are applied, successfully or not, with their heights
+    applied: BTreeMap<v2::StateHash, u32>,
+}
+
+impl Replayer {
+    /// How many heights below the highest applied block the staged ledgers are kept.
+    const KEEP: u32 = 16;
+
+    /// Runs the replay on its own thread, constructing the staged ledger of the root
+    /// takes a while and must not hold up the network. Every message on the returned
+    /// channel tells that blocks were stored, they are applied as they arrive.
+    pub fn spawn<D>(db: Arc<D>, constants: ConstraintConstants) -> mpsc::UnboundedSender<()>
+    where
+        D: Storage,
+    {
+        let (tx, mut rx) = mpsc::unbounded_channel::<()>();
+        thread::spawn(move || {
+            let mut replayer = match Self::new(&*db, constants) {
+                Ok(v) => v,
+                Err(err) => {
+                    log::error!("cannot replay the staged ledger: {err}");
+                    return;
+                }
+            };
+            while rx.blocking_recv().is_some() {
+                // one sync applies the blocks of all messages received so far
+                while rx.try_recv().is_ok() {}
+                if let Err(err) = replayer.sync(&*db) {
+                    log::error!("replay: {err}");
+                }
+            }
+        });
+
+        tx
+    }
+
+    /// Constructs the staged ledger of the root and applies all stored blocks above it.
+    fn new<D>(db: &D, constants: ConstraintConstants) -> Result<Self, Error>
+    where
+        D: Storage,
+    {
+        let (hash, state) = State::root(db, &constants)?;
+        log::info!("replay from the root {} {hash}", state.height);
+
+        let mut replayer = Replayer {
+            constants,
+            applied: Some((hash.clone(), state.height)).into_iter().collect(),
+            states: Some((hash, state)).into_iter().collect(),
+        };
+        replayer.sync(db)?;
+
+        Ok(replayer)
+    }
+
+    /// Applies every stored block which is not applied yet and whose parent's staged ledger is kept,
+    /// stores the results. Any divergence is logged as an error.
+    pub fn sync<D>(&mut self, db: &D) -> Result<(), Error>
+    where
+        D: Storage,
+    {
+        let Some(from) = self.states.values().map(|s| s.height).min() else {
+            return Ok(());
+        };
+
+        for item in db.block(BlockId::Forward(from + 1)) {
+            let (height, hashes) = item?;
+            for hash in hashes {
+                if self.applied.contains_key(&hash) {
+                    continue;
+                }
+                let block = db.block_full(&hash)?;
+                let prev_hash = &block.header.protocol_state.previous_state_hash;
+                let Some(parent) = self.states.get(prev_hash) else {
+                    continue;
+                };
+
+                let (result, state) = parent.apply(&block, &self.constants);
+                if result == ReplayResult::Ok {
+                    log::info!("replay {height} {hash} ok");
+                } else {
+                    log::error!("replay {height} {hash} diverged: {result:?}");
+                }
+                db.put_replay_result(&hash, &result)?;
+                self.applied.insert(hash.clone(), height);
+                if let Some(state) = state {
+                    self.states.insert(hash, state);
+                }
+            }
+            self.prune();
+        }
+
+        Ok(())
+    }
+
+    fn prune(&mut self) {
+        let Some(max) = self.applied.values().max().copied() else {
+            return;
+        };
+        let min = max.saturating_sub(Self::KEEP);
+        self.states.retain(|_, state| state.height >= min);
+        self.applied.retain(|_, height| *height >= min);
+    }
+}
@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 
 use mina_p2p_messages::v2;
 
@@ -10,21 +10,37 @@ use warp::{
 
 use tokio::{signal, sync::mpsc};
 
-use super::db::{Db, DbError, BlockId};
+use super::{
+    db::{DbError, BlockId, Storage},
+    snapshot::Snapshots,
+};
 
-pub fn spawn(db: Arc<Db>, port: u16, tx: mpsc::UnboundedSender<v2::StateHash>) {
-    let (addr, server) =
-        warp::serve(routes(db, tx)).bind_with_graceful_shutdown(([0; 4], port), async move {
+pub fn spawn<D>(
+    db: Arc<D>,
+    port: u16,
+    tx: mpsc::UnboundedSender<v2::StateHash>,
+    snapshots: Option<Arc<Snapshots>>,
+) where
+    D: Storage,
+{
+    let (addr, server) = warp::serve(routes(db, tx, snapshots)).bind_with_graceful_shutdown(
+        ([0; 4], port),
+        async move {
             signal::ctrl_c().await.unwrap_or_default();
-        });
+        },
+    );
     log::info!("running server on {addr}");
     tokio::spawn(server);
 }
 
-fn routes(
-    db: Arc<Db>,
+fn routes<D>(
+    db: Arc<D>,
     tx: mpsc::UnboundedSender<v2::StateHash>,
-) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + Sync + Send + 'static {
+    snapshots: Option<Arc<Snapshots>>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    D: Storage,
+{
     use warp::reply::with;
 
     let cors_filter = warp::cors()
@@ -96,7 +112,13 @@ fn routes(
         let tx = tx.clone();
         move |hash| -> WithStatus<Json> {
             if let Ok(hash) = serde_json::from_str(&format!("\"{hash}\"")) {
-                tx.send(hash).unwrap_or_default();
+                // nobody receives on a read-only instance
+                if tx.send(hash).is_err() {
+                    return reply::with_status(
+                        reply::json(&"read-only"),
+                        StatusCode::SERVICE_UNAVAILABLE,
+                    );
+                }
                 reply::with_status(reply::json(&"enqueued"), StatusCode::OK)
             } else {
                 reply::with_status(reply::json(&()), StatusCode::BAD_REQUEST)
@@ -104,21 +126,72 @@ fn routes(
         }
     });
 
+    let get_replay_divergences = warp::path!("replay" / "divergences").and(warp::get()).map({
+        let db = db.clone();
+        move || -> reply::WithStatus<Json> {
+            match db.replay_divergences() {
+                Ok(v) => reply::with_status(reply::json(&v), StatusCode::OK),
+                Err(err) => reply::with_status(
+                    reply::json(&err.to_string()),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                ),
+            }
+        }
+    });
+
+    // admin, available if the archive runs with `--snapshot-dir`,
+    // only to local clients that are not browsers, so pages cannot trigger it
+    let post_snapshot = warp::path!("snapshot")
+        .and(warp::post())
+        .and(warp::addr::remote())
+        .and(warp::header::optional::<String>("origin"))
+        .and_then(move |addr: Option<SocketAddr>, origin: Option<String>| {
+            let snapshots = snapshots.clone();
+            async move {
+                let local = addr.is_some_and(|addr| addr.ip().is_loopback());
+                if !local || origin.is_some() {
+                    return Ok::<_, Rejection>(reply::with_status(
+                        reply::json(&"forbidden"),
+                        StatusCode::FORBIDDEN,
+                    ));
+                }
+                let Some(snapshots) = snapshots else {
+                    return Ok(reply::with_status(
+                        reply::json(&"snapshots are disabled"),
+                        StatusCode::NOT_FOUND,
+                    ));
+                };
+                // the checkpoint blocks on the disk
+                let result = tokio::task::spawn_blocking(move || snapshots.create())
+                    .await
+                    .map_err(|err| err.to_string())
+                    .and_then(|r| r.map_err(|err| err.to_string()));
+                Ok(match result {
+                    Ok(manifest) => reply::with_status(reply::json(&manifest), StatusCode::OK),
+                    Err(err) => {
+                        reply::with_status(reply::json(&err), StatusCode::INTERNAL_SERVER_ERROR)
+                    }
+                })
+            }
+        })
+        .with(with::header("Content-Type", "application/json"));
+
     let get_root_ledger = warp::path!("ledger").and(warp::get()).map({
         let db = db.clone();
         move || -> reply::WithStatus<Vec<u8>> {
-            use std::collections::BTreeSet;
             use mina_p2p_messages::binprot::BinProtWrite;
             use crate::db::BlockHeader;
 
-            fn get(db: &Db) -> Result<impl BinProtWrite, DbError> {
+            fn get<D>(db: &D) -> Result<impl BinProtWrite, DbError>
+            where
+                D: Storage,
+            {
                 let root = db.root()?;
                 let (actual_root, hashes) = db
-                    .block(BlockId::Forward(root))
+                    .block(BlockId::Canonical(root))
                     .next()
                     .ok_or(DbError::RootNotFound)??;
-                if actual_root != root || hashes.iter().cloned().collect::<BTreeSet<_>>().len() != 1
-                {
+                if actual_root != root {
                     return Err(DbError::RootNotFound);
                 }
                 let hash = hashes[0].clone();
@@ -130,7 +203,7 @@ fn routes(
                 Ok((ledger, aux))
             }
 
-            match get(&db) {
+            match get(&*db) {
                 Ok(v) => {
                     let mut bytes = vec![];
                     v.binprot_write(&mut bytes).unwrap();
@@ -148,7 +221,10 @@ fn routes(
         move |height: u32| -> reply::WithStatus<Vec<u8>> {
             use mina_p2p_messages::binprot::BinProtWrite;
 
-            fn get(db: &Db, height: u32) -> Result<Option<impl BinProtWrite>, DbError> {
+            fn get<D>(db: &D, height: u32) -> Result<Option<impl BinProtWrite>, DbError>
+            where
+                D: Storage,
+            {
                 let Some((_, hashes)) = db.block(BlockId::Forward(height)).next().transpose()? else {
                     return Ok(None);
                 };
@@ -157,7 +233,7 @@ fn routes(
                 Ok(Some(blocks))
             }
 
-            match get(&db, height) {
+            match get(&*db, height) {
                 Err(err) => {
                     reply::with_status(err.to_string().as_bytes().to_vec(), StatusCode::BAD_REQUEST)
 
@@ -180,7 +256,9 @@ fn routes(
         .or(get_root)
         .or(get_brief)
         .or(post_append)
+        .or(get_replay_divergences)
         .with(with::header("Content-Type", "application/json"));
 
-    json.or(binary).with(cors_filter)
+    // the admin routes are not allowed cross origin
+    json.or(binary).with(cors_filter).or(post_snapshot)
 }
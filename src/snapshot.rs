@@ -0,0 +1,114 @@
+use std::{
+    fs, io,
+    path::PathBuf,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+
+use super::db::{Db, DbError, BlockId, Storage};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+    #[error("{0}")]
+    Db(#[from] DbError),
+}
+
+/// Describes the snapshot, stored next to it in `manifest.json`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub name: String,
+    /// Seconds since the unix epoch.
+    pub created: u64,
+    pub root: u32,
+    pub head: Option<u32>,
+    pub schema_version: Option<u32>,
+    /// The version of the archive, the same as `/version` returns.
+    pub git_hash: String,
+}
+
+/// Snapshots of the database in the directory, each is `<seconds since epoch>/db`
+/// with the `manifest.json` next to it. Unchanged files are shared by hard links,
+/// so a snapshot takes only the space of what changed since the previous one.
+/// Only the RocksDB storage can be snapshotted.
+pub struct Snapshots {
+    pub db: Arc<Db>,
+    pub dir: PathBuf,
+    /// How many latest snapshots to keep, all if `0`.
+    pub keep: usize,
+}
+
+impl Snapshots {
+    pub fn create(&self) -> Result<Manifest, Error> {
+        let db = &self.db;
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let name = created.to_string();
+        let path = self.dir.join(&name);
+        fs::create_dir_all(&path)?;
+
+        // read before the checkpoint, so the snapshot has at least these blocks
+        let root = db.root()?;
+        let head = db
+            .block(BlockId::Latest)
+            .next()
+            .transpose()?
+            .map(|(height, _)| height);
+        let schema_version = db.schema_version()?;
+        db.checkpoint(path.join("db"))?;
+
+        let manifest = Manifest {
+            name,
+            created,
+            root,
+            head,
+            schema_version,
+            git_hash: env!("GIT_HASH").to_owned(),
+        };
+        let file = fs::File::create(path.join("manifest.json"))?;
+        serde_json::to_writer_pretty(file, &manifest)?;
+        log::info!("snapshot {}, root: {root}, head: {head:?}", manifest.name);
+
+        self.prune()?;
+
+        Ok(manifest)
+    }
+
+    /// The manifests of the complete snapshots, oldest first.
+    pub fn list(&self) -> Result<Vec<Manifest>, Error> {
+        let mut manifests = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path().join("manifest.json");
+            if path.exists() {
+                let file = fs::File::open(path)?;
+                manifests.push(serde_json::from_reader::<_, Manifest>(file)?);
+            }
+        }
+        manifests.sort_by_key(|manifest| manifest.created);
+
+        Ok(manifests)
+    }
+
+    fn prune(&self) -> Result<(), Error> {
+        if self.keep == 0 {
+            return Ok(());
+        }
+
+        let manifests = self.list()?;
+        let outdated = manifests.len().saturating_sub(self.keep);
+        for manifest in &manifests[..outdated] {
+            log::info!("remove snapshot {}", manifest.name);
+            fs::remove_dir_all(self.dir.join(&manifest.name))?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,8 +1,12 @@
-use std::{io, future::Future, pin::Pin, ops::DerefMut};
+use std::{
+    io,
+    ops::DerefMut,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+};
 
 use thiserror::Error;
 
-use libp2p::futures::Stream;
+use libp2p::{futures::Stream, PeerId};
 
 use mina_p2p_messages::{
     binprot::{self, BinProtWrite, BinProtRead},
@@ -12,13 +16,17 @@ use mina_p2p_messages::{
 };
 use mina_tree::{Mask, Database, Account, BaseLedger, Address, AccountIndex};
 
-use super::client::{Client, TSwarmEvent, TSwarm};
+use super::{
+    client::{Client, ClientError, TSwarmEvent, TSwarm},
+    db::{DbError, Storage},
+};
 
 pub struct SnarkedLedger {
     pub inner: Mask,
     // NOTE: it is not the same as the merkle tree root
     pub top_hash: Option<v2::LedgerHash>,
     pub num: u32,
+    pub depth: u8,
 }
 
 #[derive(Debug, Error)]
@@ -27,17 +35,80 @@ pub enum Error {
     Serde(#[from] serde_json::Error),
     #[error("{0}")]
     Io(#[from] io::Error),
+    #[error("{0}")]
+    Client(#[from] ClientError),
+    #[error("{0}")]
+    Db(#[from] DbError),
+    #[error("peer cannot answer: {0}")]
+    Peer(String),
+    #[error("unexpected answer to {0}")]
+    UnexpectedAnswer(String),
+    #[error("hash mismatch at depth {depth} pos {pos}, expected {expected}, actual {actual}")]
+    HashMismatch {
+        depth: i32,
+        pos: u32,
+        expected: v2::LedgerHash,
+        actual: v2::LedgerHash,
+    },
 }
 
 impl SnarkedLedger {
-    pub fn empty() -> Self {
+    pub fn empty(depth: u8) -> Self {
         SnarkedLedger {
-            inner: Mask::new_root(Database::create(35)),
+            inner: Mask::new_root(Database::create(depth)),
             top_hash: None,
             num: 0,
+            depth,
+        }
+    }
+
+    pub fn from_accounts(depth: u8, accounts: &[v2::MinaBaseAccountBinableArgStableV2]) -> Self {
+        let mut inner = Mask::new_root(Database::create(depth));
+        for account in accounts {
+            let account = Account::from(account);
+            let account_id = account.id();
+            inner.get_or_create_account(account_id, account).unwrap();
+        }
+
+        let _ = inner.merkle_root();
+
+        SnarkedLedger {
+            inner,
+            top_hash: None,
+            num: accounts.len() as _,
+            depth,
         }
     }
 
+    /// The ledger ready to answer sync queries, with the hash of the subtree containing all accounts.
+    pub fn for_serving(depth: u8, accounts: &[v2::MinaBaseAccountBinableArgStableV2]) -> Self {
+        let mut ledger = Self::from_accounts(depth, accounts);
+        let num = accounts.len() as u32;
+        let height = if num <= 1 {
+            0
+        } else {
+            32 - (num - 1).leading_zeros()
+        };
+        let addr = Address::from_index(AccountIndex(0), (depth as u32 - height) as _);
+        let hash = ledger.inner.get_inner_hash_at_addr(addr).unwrap();
+        ledger.top_hash = Some(v2::LedgerHash::from(v2::MinaBaseLedgerHash0StableV1(
+            hash.into(),
+        )));
+        ledger
+    }
+
+    /// The hash of the ledger as it appears in the block.
+    pub fn merkle_root(&mut self) -> v2::LedgerHash {
+        let hash = self.inner.merkle_root();
+        v2::LedgerHash::from(v2::MinaBaseLedgerHash0StableV1(hash.into()))
+    }
+
+    pub fn accounts(&self) -> Vec<v2::MinaBaseAccountBinableArgStableV2> {
+        let mut accounts = vec![];
+        self.inner.iter(|account| accounts.push(account.into()));
+        accounts
+    }
+
     // for debugging
     #[allow(dead_code)]
     pub fn store_bin<W>(&self, mut writer: W) -> io::Result<()>
@@ -53,7 +124,7 @@ impl SnarkedLedger {
     }
 
     #[allow(dead_code)]
-    pub fn load_bin<R>(mut reader: R) -> Result<Self, binprot::Error>
+    pub fn load_bin<R>(depth: u8, mut reader: R) -> Result<Self, binprot::Error>
     where
         R: io::Read,
     {
@@ -61,7 +132,7 @@ impl SnarkedLedger {
         let accounts = Vec::<Account>::binprot_read(&mut reader)?;
 
         let num = accounts.len() as _;
-        let mut inner = Mask::new_root(Database::create(35));
+        let mut inner = Mask::new_root(Database::create(depth));
         for account in accounts {
             let account_id = account.id();
             inner.get_or_create_account(account_id, account).unwrap();
@@ -73,136 +144,216 @@ impl SnarkedLedger {
             inner,
             top_hash,
             num,
+            depth,
         })
     }
 
-    pub async fn sync_new<S>(&mut self, client: &mut Client<S>, root: &v2::LedgerHash)
+    /// Maximal number of queries in flight during the sync.
+    const WINDOW: usize = 64;
+    const ATTEMPTS: usize = 4;
+
+    /// Syncs the ledger breadth-first, keeps up to `WINDOW` queries in flight.
+    /// Subtrees whose hash already matches are skipped, so syncing on top of
+    /// the previous ledger only fetches the changes.
+    /// If `progress` is given, the answers are stored there by the ledger hash,
+    /// a restarted sync of the same ledger takes them instead of querying peers again.
+    pub async fn sync_new<S, D>(
+        &mut self,
+        client: &mut Client<S, D>,
+        root: &v2::LedgerHash,
+        progress: Option<&D>,
+    ) -> Result<(), Error>
     where
         S: Unpin + Send + Stream<Item = TSwarmEvent> + DerefMut<Target = TSwarm>,
+        D: Storage,
     {
         let q = v2::MinaLedgerSyncLedgerQueryStableV1::NumAccounts;
         let r = client
             .rpc::<AnswerSyncLedgerQueryV2>((root.0.clone(), q))
-            .await
-            .unwrap()
+            .await?
             .0
-            .unwrap();
+            .map_err(|err| Error::Peer(format!("{err:?}")))?;
         let (num, hash) = match r {
             v2::MinaLedgerSyncLedgerAnswerStableV2::NumAccounts(num, hash) => (num.0, hash),
-            _ => panic!(),
+            r => return Err(Error::UnexpectedAnswer(format!("num accounts: {r:?}"))),
         };
         self.top_hash = Some(hash.clone());
         self.num = num as _;
 
         if self.inner.num_accounts() > num as _ {
-            self.inner = Mask::new_root(Database::create(35));
+            self.inner = Mask::new_root(Database::create(self.depth));
         }
 
-        self.sync_at_depth_new(client, root.clone(), hash.clone(), 0, 0)
-            .await;
-        let actual_hash = self.inner.merkle_root();
-        let actual_hash = v2::LedgerHash::from(v2::MinaBaseLedgerHash0StableV1(actual_hash.into()));
-        assert_eq!(actual_hash, root.clone());
-    }
+        let batch_depth = self.batch_depth();
 
-    fn sync_at_depth_boxed_new<'a, 'b: 'a, S>(
-        &'b mut self,
-        client: &'a mut Client<S>,
-        root: v2::LedgerHash,
-        hash: v2::LedgerHash,
-        depth: i32,
-        pos: u32,
-    ) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>>
-    where
-        S: Unpin + Send + Stream<Item = TSwarmEvent> + DerefMut<Target = TSwarm>,
-    {
-        Box::pin(self.sync_at_depth_new(client, root, hash, depth, pos))
-    }
-
-    async fn sync_at_depth_new<S>(
-        &mut self,
-        client: &mut Client<S>,
-        root: v2::LedgerHash,
-        hash: v2::LedgerHash,
-        depth: i32,
-        pos: u32,
-    ) where
-        S: Unpin + Send + Stream<Item = TSwarmEvent> + DerefMut<Target = TSwarm>,
-    {
-        let addr = Address::from_index(AccountIndex(pos as _), depth as _);
-        let actual_hash = self.inner.get_inner_hash_at_addr(addr.clone()).unwrap();
-        if depth == 0 && root.0 == actual_hash.into() || depth > 0 && hash.0 == actual_hash.into() {
-            return;
+        let mut stored = match progress {
+            Some(db) => db
+                .ledger_sync_answers(root)
+                .map(|r| r.map(|(depth, pos, answer)| ((depth, pos), answer)))
+                .collect::<Result<BTreeMap<_, _>, _>>()?,
+            None => BTreeMap::new(),
+        };
+        if !stored.is_empty() {
+            log::info!(
+                "resume ledger sync, {} answers already fetched",
+                stored.len()
+            );
         }
 
-        if depth == 32 {
-            let p = pos.to_be_bytes().to_vec();
-            let q = v2::MinaLedgerSyncLedgerQueryStableV1::WhatContents(
-                v2::MerkleAddressBinableArgStableV1((depth as i64).into(), p.into()),
-            );
-            log::debug!("{}", serde_json::to_string(&q).unwrap());
-            let r = client
-                .rpc::<AnswerSyncLedgerQueryV2>((root.0.clone(), q))
-                .await
-                .unwrap()
-                .0;
-            match r {
-                Err(Info::CouldNotConstruct(s)) => {
-                    log::error!(
-                        "num: {}, could not construct {}",
-                        self.num,
-                        s.to_string_lossy()
-                    );
+        // (depth, pos, expected hash)
+        let mut queue = VecDeque::from([(0, 0, root.clone())]);
+        let mut in_flight = BTreeMap::new();
+        // failed attempts of the query and the peers that failed it
+        let mut retries = BTreeMap::<(i32, u32), (usize, BTreeSet<PeerId>)>::new();
+        let no_peers = BTreeSet::new();
+        let mut visited = vec![];
+        while !queue.is_empty() || !in_flight.is_empty() {
+            while in_flight.len() < Self::WINDOW {
+                let Some((depth, pos, hash)) = queue.pop_front() else {
+                    break;
+                };
+                if self.hash_at(depth, pos) == hash {
+                    continue;
                 }
-                Ok(v2::MinaLedgerSyncLedgerAnswerStableV2::ContentsAre(accounts)) => {
-                    for (o, account) in accounts.into_iter().enumerate() {
-                        let account = Account::from(&account);
-                        self.inner
-                            .set_at_index(
-                                AccountIndex((pos * 8) as u64 + o as u64),
-                                Box::new(account),
-                            )
-                            .unwrap();
-                    }
+                if let Some(answer) = stored.remove(&(depth, pos)) {
+                    self.apply(depth, pos, answer, &mut queue)?;
+                    visited.push((depth, pos, hash));
+                    continue;
                 }
-                _ => panic!(),
+                let q = Self::query_at(depth, pos, depth == batch_depth);
+                log::debug!("{}", serde_json::to_string(&q).unwrap());
+                let exclude = retries
+                    .get(&(depth, pos))
+                    .map_or(&no_peers, |(_, peers)| peers);
+                let id = client
+                    .send::<AnswerSyncLedgerQueryV2>((root.0.clone(), q), exclude)
+                    .await?;
+                in_flight.insert(id, (depth, pos, hash, client.peer_of(id)));
             }
-        } else {
-            let b = ((depth as usize + 7) / 8).min(4);
-            let p = pos * (1 << (32 - depth));
-            let p = p.to_be_bytes()[..b].to_vec();
-            let q = v2::MinaLedgerSyncLedgerQueryStableV1::WhatChildHashes(
-                v2::MerkleAddressBinableArgStableV1((depth as i64).into(), p.into()),
-            );
-            log::debug!("{}", serde_json::to_string(&q).unwrap());
-            let r = client
-                .rpc::<AnswerSyncLedgerQueryV2>((root.0.clone(), q))
-                .await
-                .unwrap()
-                .0
-                .unwrap();
-            match r {
-                v2::MinaLedgerSyncLedgerAnswerStableV2::ChildHashesAre(l, r) => {
-                    self.sync_at_depth_boxed_new(client, root.clone(), l, depth + 1, pos * 2)
-                        .await;
-                    self.sync_at_depth_boxed_new(client, root.clone(), r, depth + 1, pos * 2 + 1)
-                        .await;
+
+            // the stored answers may have taken the rest of the queue
+            if in_flight.is_empty() {
+                continue;
+            }
+            let (id, result) = client
+                .next_response::<AnswerSyncLedgerQueryV2, _>(in_flight.keys().copied())
+                .await?;
+            let Some((depth, pos, hash, peer_id)) = in_flight.remove(&id) else {
+                continue;
+            };
+            let answer = match result {
+                Ok(r) => match r.0 {
+                    Err(Info::CouldNotConstruct(s)) if depth == batch_depth => {
+                        log::error!(
+                            "num: {}, could not construct {}",
+                            self.num,
+                            s.to_string_lossy()
+                        );
+                        visited.push((depth, pos, hash));
+                        continue;
+                    }
+                    Ok(answer) => Ok(answer),
+                    Err(err) => Err(Error::Peer(format!("{err:?}"))),
+                },
+                Err(err) => Err(err.into()),
+            };
+            // the failed query is sent again to another peer
+            let answer = match answer {
+                Ok(v) => v,
+                Err(err) => {
+                    let (attempt, peers) = retries.entry((depth, pos)).or_default();
+                    *attempt += 1;
+                    if *attempt >= Self::ATTEMPTS {
+                        return Err(err);
+                    }
+                    log::warn!("query at depth {depth} pos {pos} failed: {err}, retrying");
+                    peers.extend(peer_id);
+                    queue.push_front((depth, pos, hash));
+                    continue;
                 }
-                _ => panic!(),
             };
+            // only an answer of the expected kind is stored
+            self.apply(depth, pos, answer.clone(), &mut queue)?;
+            if let Some(db) = progress {
+                db.put_ledger_sync_answer(root, depth, pos, &answer)?;
+            }
+            visited.push((depth, pos, hash));
+        }
+
+        // children are visited after parents, check the deepest first
+        for (depth, pos, expected) in visited.into_iter().rev() {
+            let actual = self.hash_at(depth, pos);
+            if expected != actual {
+                // some stored answer is wrong, the next sync starts over
+                if let Some(db) = progress {
+                    db.clear_ledger_sync()?;
+                }
+                return Err(Error::HashMismatch {
+                    depth,
+                    pos,
+                    expected,
+                    actual,
+                });
+            }
         }
 
+        Ok(())
+    }
+
+    /// Sets the accounts of the batch, or queues the children of the subtree.
+    fn apply(
+        &mut self,
+        depth: i32,
+        pos: u32,
+        answer: v2::MinaLedgerSyncLedgerAnswerStableV2,
+        queue: &mut VecDeque<(i32, u32, v2::LedgerHash)>,
+    ) -> Result<(), Error> {
+        let batch_depth = self.batch_depth();
+        match answer {
+            v2::MinaLedgerSyncLedgerAnswerStableV2::ContentsAre(accounts)
+                if depth == batch_depth =>
+            {
+                for (o, account) in accounts.into_iter().enumerate() {
+                    let account = Account::from(&account);
+                    self.inner
+                        .set_at_index(AccountIndex((pos * 8) as u64 + o as u64), Box::new(account))
+                        .unwrap();
+                }
+            }
+            v2::MinaLedgerSyncLedgerAnswerStableV2::ChildHashesAre(l, r) if depth < batch_depth => {
+                queue.push_back((depth + 1, pos * 2, l));
+                queue.push_back((depth + 1, pos * 2 + 1, r));
+            }
+            r => return Err(Error::UnexpectedAnswer(format!("depth {depth}: {r:?}"))),
+        }
+
+        Ok(())
+    }
+
+    fn hash_at(&mut self, depth: i32, pos: u32) -> v2::LedgerHash {
         let addr = Address::from_index(AccountIndex(pos as _), depth as _);
-        let actual_hash = self.inner.get_inner_hash_at_addr(addr).unwrap();
-        let actual_hash = v2::LedgerHash::from(v2::MinaBaseLedgerHash0StableV1(actual_hash.into()));
-        if depth == 0 {
-            assert_eq!(root, actual_hash);
+        let hash = self.inner.get_inner_hash_at_addr(addr).unwrap();
+        v2::LedgerHash::from(v2::MinaBaseLedgerHash0StableV1(hash.into()))
+    }
+
+    /// Accounts are fetched in batches of 8, so the depth of the batch is 3 less than the ledger.
+    fn batch_depth(&self) -> i32 {
+        self.depth as i32 - 3
+    }
+
+    fn query_at(depth: i32, pos: u32, contents: bool) -> v2::MinaLedgerSyncLedgerQueryStableV1 {
+        // the address is the position as a big-endian bit string of the `depth` length
+        let b = ((depth as usize + 7) / 8).min(4);
+        let p = ((pos as u64) << (32 - depth)) as u32;
+        let p = p.to_be_bytes()[..b].to_vec();
+        let address = v2::MerkleAddressBinableArgStableV1((depth as i64).into(), p.into());
+        if contents {
+            v2::MinaLedgerSyncLedgerQueryStableV1::WhatContents(address)
         } else {
-            assert_eq!(hash, actual_hash);
+            v2::MinaLedgerSyncLedgerQueryStableV1::WhatChildHashes(address)
         }
     }
 
-    #[allow(dead_code)]
     pub fn serve_query(
         &mut self,
         q: v2::MinaLedgerSyncLedgerQueryStableV1,
@@ -240,7 +391,7 @@ impl SnarkedLedger {
 
                 let mut accounts = Vec::with_capacity(8);
                 let mut offset = 0;
-                let batch_length = 1u64 << (35 - depth);
+                let batch_length = 1u64 << (self.depth as usize - depth);
                 loop {
                     if offset == batch_length {
                         break;
@@ -251,7 +402,7 @@ impl SnarkedLedger {
                     if pos == self.num as u64 {
                         break;
                     }
-                    let addr = Address::from_index(AccountIndex(pos as _), 35);
+                    let addr = Address::from_index(AccountIndex(pos as _), self.depth as _);
                     let account = self.inner.get(addr);
                     if let Some(account) = account {
                         accounts.push((&*account).into());
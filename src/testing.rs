@@ -0,0 +1,50 @@
+// Values and directories for the tests. The values are decoded from the binprot encoding,
+// so every field the test does not set is zero or empty.
+
+use std::path::PathBuf;
+
+use mina_p2p_messages::binprot::BinProtRead;
+
+/// The value with every field zero or empty, such as a block or an account.
+pub fn empty<T>() -> T
+where
+    T: BinProtRead,
+{
+    let zeros = vec![0; 0x10000];
+    T::binprot_read(&mut zeros.as_slice()).unwrap()
+}
+
+/// Decodes the value from the binprot encoding of the small number,
+/// it fits any of the numbers of the block.
+pub fn number<T>(n: u8) -> T
+where
+    T: BinProtRead,
+{
+    assert!(n < 0x80, "larger numbers take more than one byte");
+    T::binprot_read(&mut [n].as_slice()).unwrap()
+}
+
+/// Decodes the hash from the field element with the given lowest byte.
+pub fn field<T>(n: u8) -> T
+where
+    T: BinProtRead,
+{
+    let mut bytes = [0; 32];
+    bytes[0] = n;
+    T::binprot_read(&mut bytes.as_slice()).unwrap()
+}
+
+/// Decodes the byte string of the single byte.
+pub fn bytes<T>(n: u8) -> T
+where
+    T: BinProtRead,
+{
+    T::binprot_read(&mut [1, n].as_slice()).unwrap()
+}
+
+/// A fresh directory for the database of the test.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("openmina-archive-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    path
+}
@@ -0,0 +1,259 @@
+use blake2::{Blake2b, Digest, digest::consts::U32};
+use mina_p2p_messages::{binprot::BinProtWrite, v2};
+use thiserror::Error;
+
+use super::db::BlockHeader;
+
+#[derive(Debug, Error)]
+pub enum ValidationError {
+    #[error("state hash mismatch, expected {expected}, actual {actual}")]
+    HashMismatch {
+        expected: v2::StateHash,
+        actual: v2::StateHash,
+    },
+    #[error("block was not requested")]
+    NotRequested,
+    #[error("block refers to itself as the parent")]
+    SelfParent,
+    #[error("zero blockchain length")]
+    ZeroLength,
+    #[error("parent {0} is unknown and cannot be fetched")]
+    ParentUnknown(v2::StateHash),
+    #[error("blockchain length {actual} is not above the root {root}")]
+    BelowRoot { root: u32, actual: u32 },
+    #[error("blockchain length {actual} does not follow the parent {parent}")]
+    BadLength { parent: u32, actual: u32 },
+    #[error("global slot {actual} is not after the parent {parent}")]
+    BadSlot { parent: u32, actual: u32 },
+    #[error("epoch {actual} is before the parent {parent}")]
+    BadEpoch { parent: u32, actual: u32 },
+}
+
+fn global_slot(block: &v2::MinaBlockBlockStableV2) -> u32 {
+    let v2::MinaNumbersGlobalSlotSinceGenesisMStableV1::SinceGenesis(slot) = &block
+        .header
+        .protocol_state
+        .body
+        .consensus_state
+        .global_slot_since_genesis;
+    slot.as_u32()
+}
+
+fn epoch(block: &v2::MinaBlockBlockStableV2) -> u32 {
+    block
+        .header
+        .protocol_state
+        .body
+        .consensus_state
+        .epoch_count
+        .as_u32()
+}
+
+/// The size limit of a bitswap block, the body is split into the blocks of this size.
+const BITSWAP_BLOCK_SIZE: usize = 262144;
+const BITSWAP_LINK_SIZE: usize = 32;
+/// The bitswap tag of the block body, it prefixes the serialized body.
+const BODY_TAG: u8 = 0;
+
+/// Splits the data into bitswap blocks, returns them in breadth-first order, the root first.
+/// Each block is the number of links as little endian `u16`, the links and a chunk of the data.
+/// Every block except the last one is full, the chunks in breadth-first order make the data.
+fn bitswap_blocks(data: &[u8], max_block_size: usize) -> Vec<Vec<u8>> {
+    let chunk_size = max_block_size - 2;
+    let max_links = (chunk_size / BITSWAP_LINK_SIZE).min(u16::MAX as usize);
+
+    // every block except the root is linked once, every link takes the place of the data
+    let num_blocks = if data.len() <= chunk_size {
+        1
+    } else {
+        let capacity = chunk_size - BITSWAP_LINK_SIZE;
+        (data.len() - BITSWAP_LINK_SIZE + capacity - 1) / capacity
+    };
+
+    let mut links = Vec::with_capacity(num_blocks);
+    let mut remaining = num_blocks - 1;
+    for _ in 0..num_blocks {
+        let n = remaining.min(max_links);
+        links.push(n);
+        remaining -= n;
+    }
+
+    let mut chunks = Vec::with_capacity(num_blocks);
+    let mut pos = 0;
+    for n in &links[..(num_blocks - 1)] {
+        let end = pos + chunk_size - n * BITSWAP_LINK_SIZE;
+        chunks.push(&data[pos..end]);
+        pos = end;
+    }
+    chunks.push(&data[pos..]);
+
+    // the children of a block follow the children of the previous blocks
+    let mut first_child = Vec::with_capacity(num_blocks);
+    let mut next = 1;
+    for n in &links {
+        first_child.push(next);
+        next += n;
+    }
+
+    let mut blocks = vec![vec![]; num_blocks];
+    for i in (0..num_blocks).rev() {
+        let mut block = Vec::with_capacity(max_block_size);
+        block.extend_from_slice(&(links[i] as u16).to_le_bytes());
+        for child in &blocks[first_child[i]..(first_child[i] + links[i])] {
+            block.extend_from_slice(&Blake2b::<U32>::digest(child));
+        }
+        block.extend_from_slice(chunks[i]);
+        blocks[i] = block;
+    }
+
+    blocks
+}
+
+fn body_reference(body: &v2::StagedLedgerDiffBodyStableV1) -> [u8; 32] {
+    let mut data = vec![BODY_TAG];
+    body.binprot_write(&mut data).expect("write to vec");
+    let blocks = bitswap_blocks(&data, BITSWAP_BLOCK_SIZE);
+    Blake2b::<U32>::digest(&blocks[0]).into()
+}
+
+/// Checks that do not need the parent. If the block was requested by hash,
+/// the `expected` hash must match the recomputed one.
+pub fn check(
+    block: &v2::MinaBlockBlockStableV2,
+    expected: Option<&v2::StateHash>,
+) -> Result<v2::StateHash, ValidationError> {
+    let hash = block.hash();
+    if let Some(expected) = expected {
+        if *expected != hash {
+            return Err(ValidationError::HashMismatch {
+                expected: expected.clone(),
+                actual: hash,
+            });
+        }
+    }
+    if block.header.protocol_state.previous_state_hash == hash {
+        return Err(ValidationError::SelfParent);
+    }
+    if block.height() == 0 {
+        return Err(ValidationError::ZeroLength);
+    }
+    let expected = &block
+        .header
+        .protocol_state
+        .body
+        .blockchain_state
+        .body_reference;
+    // not rejected until the hash layout is checked against the blocks of the network
+    if expected.0.as_ref() != body_reference(&block.body).as_slice() {
+        log::warn!("body reference of {hash} does not match the staged ledger diff");
+    }
+
+    Ok(hash)
+}
+
+/// Consensus sanity checks of the block against its parent.
+pub fn check_with_parent(
+    block: &v2::MinaBlockBlockStableV2,
+    parent: &v2::MinaBlockBlockStableV2,
+) -> Result<(), ValidationError> {
+    if block.height() != parent.height() + 1 {
+        return Err(ValidationError::BadLength {
+            parent: parent.height(),
+            actual: block.height(),
+        });
+    }
+    if global_slot(block) <= global_slot(parent) {
+        return Err(ValidationError::BadSlot {
+            parent: global_slot(parent),
+            actual: global_slot(block),
+        });
+    }
+    if epoch(block) < epoch(parent) {
+        return Err(ValidationError::BadEpoch {
+            parent: epoch(parent),
+            actual: epoch(block),
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, fs::File, io::BufReader, path::PathBuf};
+
+    use blake2::{Blake2b, Digest, digest::consts::U32};
+    use mina_p2p_messages::{binprot::BinProtRead, v2};
+
+    use super::{bitswap_blocks, body_reference, BITSWAP_LINK_SIZE};
+
+    /// Joins the blocks back following the links from the root, breadth-first.
+    fn join(blocks: &[Vec<u8>]) -> Vec<u8> {
+        let by_hash = blocks
+            .iter()
+            .map(|block| (Blake2b::<U32>::digest(block).to_vec(), block))
+            .collect::<BTreeMap<_, _>>();
+
+        let mut data = vec![];
+        let mut queue = vec![&blocks[0]];
+        while !queue.is_empty() {
+            let mut next = vec![];
+            for block in queue {
+                let links = u16::from_le_bytes([block[0], block[1]]) as usize;
+                let chunk_start = 2 + links * BITSWAP_LINK_SIZE;
+                for link in block[2..chunk_start].chunks(BITSWAP_LINK_SIZE) {
+                    next.push(by_hash[link]);
+                }
+                data.extend_from_slice(&block[chunk_start..]);
+            }
+            queue = next;
+        }
+
+        data
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn single_block() {
+        let data = data(98);
+        let blocks = bitswap_blocks(&data, 100);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0][..2], [0, 0]);
+        assert_eq!(blocks[0][2..], data[..]);
+    }
+
+    #[test]
+    fn split_blocks() {
+        // a block of 100 bytes holds at most 3 links, so the tree has several levels
+        for len in [99, 100, 500, 1000, 5000] {
+            let data = data(len);
+            let blocks = bitswap_blocks(&data, 100);
+            assert!(blocks.len() > 1);
+            assert!(blocks.iter().all(|block| block.len() <= 100));
+            // every block except the last one is full
+            let last = blocks.len() - 1;
+            assert!(blocks[..last].iter().all(|block| block.len() == 100));
+            assert_eq!(join(&blocks), data);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs fixtures/gossip_block, a block of the network in binprot"]
+    fn network_body_reference() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join("gossip_block");
+        let mut file = BufReader::new(File::open(path).unwrap());
+        let block = v2::MinaBlockBlockStableV2::binprot_read(&mut file).unwrap();
+        let expected = &block
+            .header
+            .protocol_state
+            .body
+            .blockchain_state
+            .body_reference;
+        assert_eq!(expected.0.as_ref(), body_reference(&block.body).as_slice());
+    }
+}
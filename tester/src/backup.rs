@@ -1,17 +1,89 @@
 use std::{
-    path::PathBuf,
+    path::{PathBuf, Path},
     fs::{File, self},
-    io::{self, Write},
+    io::{self, Write, Seek, SeekFrom, BufReader, Read},
 };
 
-pub fn run(path: PathBuf, ledger_bytes: &[u8], blocks: impl Iterator<Item = impl io::Read>) {
+use bytes::Bytes;
+use openmina_archive_profile::IndexEntry;
+
+use crate::Blocks;
+
+/// Writes the `ledger` file, the `blocks` file with the blocks of every level one after another,
+/// and the `index` file, the `json` array of `IndexEntry`. The index is written last,
+/// a backup without it is incomplete.
+pub fn run(path: PathBuf, ledger_bytes: &[u8], blocks: Blocks) {
     fs::create_dir_all(&path).unwrap_or_default();
 
     let mut ledger_file = File::create(path.join("ledger")).unwrap();
     ledger_file.write_all(ledger_bytes).unwrap();
 
     let mut blocks_file = File::create(path.join("blocks")).unwrap();
-    for mut transitions in blocks {
-        io::copy(&mut transitions, &mut blocks_file).unwrap();
+    let mut index = vec![];
+    let mut offset = 0;
+    for (height, mut transitions) in blocks {
+        let length = io::copy(&mut transitions, &mut blocks_file).unwrap();
+        index.push(IndexEntry {
+            height,
+            offset,
+            length,
+        });
+        offset += length;
+    }
+
+    let index_file = File::create(path.join("index")).unwrap();
+    serde_json::to_writer(index_file, &index).unwrap();
+}
+
+/// Reads the backup written by `run`, the blocks start from the given height if any.
+pub fn load(path: &Path, from: Option<u32>) -> (Bytes, Blocks) {
+    let ledger_bytes = Bytes::from(fs::read(path.join("ledger")).unwrap());
+
+    let index_file = File::open(path.join("index")).expect("the backup has no index");
+    let index = serde_json::from_reader::<_, Vec<IndexEntry>>(index_file).unwrap();
+
+    let blocks_path = path.join("blocks");
+    let blocks = index
+        .into_iter()
+        .filter(move |entry| from.map_or(true, |from| entry.height >= from))
+        .map(move |entry| {
+            let mut file = File::open(&blocks_path).unwrap();
+            file.seek(SeekFrom::Start(entry.offset)).unwrap();
+            let reader = BufReader::new(file.take(entry.length));
+            (entry.height, Box::new(reader) as Box<dyn Read>)
+        });
+
+    (ledger_bytes, Box::new(blocks))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::{load, run};
+
+    #[test]
+    fn index_roundtrip() {
+        let path = std::env::temp_dir().join(format!("tester-backup-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+
+        let levels: [(u32, &[u8]); 3] = [(1, b"aa"), (2, b"bbb"), (3, b"c")];
+        let blocks = levels
+            .into_iter()
+            .map(|(height, bytes)| (height, Box::new(bytes) as Box<dyn Read>));
+        run(path.clone(), b"ledger", Box::new(blocks));
+
+        let (ledger, blocks) = load(&path, Some(2));
+        assert_eq!(ledger.as_ref(), b"ledger");
+        let blocks = blocks
+            .map(|(height, mut reader)| {
+                let mut bytes = vec![];
+                reader.read_to_end(&mut bytes).unwrap();
+                (height, bytes)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(blocks, vec![(2, b"bbb".to_vec()), (3, b"c".to_vec())]);
+
+        let _ = std::fs::remove_dir_all(path);
     }
 }
@@ -0,0 +1,193 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use mina_p2p_messages::{
+    binprot::{self, BinProtWrite},
+    rpc::GetStagedLedgerAuxAndPendingCoinbasesAtHashV2Response as Aux,
+    v2,
+};
+use mina_tree::scan_state::scan_state::ConstraintConstants;
+use thiserror::Error;
+
+use crate::{
+    bootstrap::{self, Storage},
+    report::ApplyResult,
+};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Binprot(#[from] binprot::Error),
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+    #[error("no blocks")]
+    NoBlocks,
+    #[error("head {0} not found")]
+    HeadNotFound(String),
+    #[error("the highest level {0} has several blocks, choose the head")]
+    AmbiguousHead(u32),
+    #[error("the parent {0} of the chain is unknown")]
+    UnknownParent(v2::StateHash),
+}
+
+/// Finds the first block of the chain ending at `head` whose replay disagrees
+/// with the staged ledger hash in its header, and dumps it to `out`.
+/// If all blocks agree, dumps the `head`, the dump is a replay fixture for the tests.
+/// The `head` is the block of the highest level if not given, that level must have one block.
+/// The chain is replayed from the root, the checkpoints are saved only after the blocks
+/// that agree, the replay before them may still diverge.
+/// Returns the result of the dumped block, `None` if the chain has only the root.
+pub fn run(
+    constants: &ConstraintConstants,
+    accounts: Vec<v2::MinaBaseAccountBinableArgStableV2>,
+    aux: Aux,
+    blocks: impl Iterator<Item = Vec<v2::MinaBlockBlockStableV2>>,
+    head: Option<String>,
+    out: &Path,
+) -> Result<Option<ApplyResult>, Error> {
+    let chain = chain(blocks, head)?;
+    let root_block = &chain[0];
+
+    let snarked_ledger = bootstrap::ledger(constants, accounts);
+    let expected_hash = root_block
+        .header
+        .protocol_state
+        .body
+        .blockchain_state
+        .staged_ledger_hash
+        .clone();
+    let (result, storage) = Storage::new(constants.clone(), snarked_ledger, aux, expected_hash);
+    let mut storage = match (result, storage) {
+        (ApplyResult::Ok, Some(storage)) => storage,
+        (result, _) => {
+            log::error!("cannot construct the staged ledger of the root");
+            return Ok(Some(result));
+        }
+    };
+
+    let mut last = None;
+    for index in 1..chain.len() {
+        let block = &chain[index];
+        log::info!("apply {}", height(block));
+        let pre_state = storage.clone();
+        let result = storage.apply_block(block, &chain[index - 1].header.protocol_state);
+        let diverged = !result.is_ok();
+        last = Some((index, pre_state, result));
+        if diverged {
+            break;
+        }
+    }
+
+    let Some((index, pre_state, result)) = last else {
+        log::info!("the chain has only the root");
+        return Ok(None);
+    };
+    let block = &chain[index];
+    if result.is_ok() {
+        log::info!("all {} blocks agree", chain.len() - 1);
+    } else {
+        log::info!(
+            "first diverged block: {}, height: {}",
+            block.hash(),
+            height(block)
+        );
+    }
+    dump(out, &chain[index - 1], block, &pre_state, &result)?;
+
+    Ok(Some(result))
+}
+
+fn height(block: &v2::MinaBlockBlockStableV2) -> u32 {
+    block
+        .header
+        .protocol_state
+        .body
+        .consensus_state
+        .blockchain_length
+        .as_u32()
+}
+
+/// The chain from the first level to the head, walked back from the head by the parent hashes.
+fn chain(
+    blocks: impl Iterator<Item = Vec<v2::MinaBlockBlockStableV2>>,
+    head: Option<String>,
+) -> Result<Vec<v2::MinaBlockBlockStableV2>, Error> {
+    let mut first = None;
+    let mut highest = (0, vec![]);
+    let mut by_hash = BTreeMap::new();
+    for level in blocks {
+        for block in level {
+            let hash = block.hash();
+            let height = height(&block);
+            first = Some(first.map_or(height, |first: u32| first.min(height)));
+            if height > highest.0 {
+                highest = (height, vec![]);
+            }
+            if height == highest.0 {
+                highest.1.push(hash.clone());
+            }
+            by_hash.insert(hash, block);
+        }
+    }
+    let first = first.ok_or(Error::NoBlocks)?;
+    let mut hash = match head {
+        Some(head) => by_hash
+            .keys()
+            .find(|hash| hash.to_string() == head)
+            .ok_or(Error::HeadNotFound(head))?
+            .clone(),
+        None => match <[_; 1]>::try_from(highest.1) {
+            Ok([hash]) => hash,
+            Err(_) => return Err(Error::AmbiguousHead(highest.0)),
+        },
+    };
+
+    let mut chain = vec![];
+    loop {
+        let block = by_hash.remove(&hash).ok_or(Error::UnknownParent(hash))?;
+        let at_first = height(&block) == first;
+        hash = block.header.protocol_state.previous_state_hash.clone();
+        chain.push(block);
+        if at_first {
+            break;
+        }
+    }
+    chain.reverse();
+
+    Ok(chain)
+}
+
+fn dump(
+    out: &Path,
+    parent: &v2::MinaBlockBlockStableV2,
+    block: &v2::MinaBlockBlockStableV2,
+    pre_state: &Storage,
+    result: &ApplyResult,
+) -> Result<(), Error> {
+    fs::create_dir_all(out)?;
+
+    let mut file = BufWriter::new(File::create(out.join("block"))?);
+    block.binprot_write(&mut file)?;
+    file.flush()?;
+
+    let mut file = BufWriter::new(File::create(out.join("parent_protocol_state"))?);
+    parent.header.protocol_state.binprot_write(&mut file)?;
+    file.flush()?;
+
+    let mut file = BufWriter::new(File::create(out.join("pre_state"))?);
+    pre_state.snapshot().binprot_write(&mut file)?;
+    file.flush()?;
+
+    let file = File::create(out.join("result.json"))?;
+    serde_json::to_writer_pretty(file, result)?;
+
+    log::info!("dumped into {}", out.display());
+
+    Ok(())
+}
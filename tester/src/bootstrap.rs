@@ -3,61 +3,83 @@ use std::collections::BTreeMap;
 use mina_p2p_messages::{rpc::GetStagedLedgerAuxAndPendingCoinbasesAtHashV2Response as Aux, v2};
 use mina_tree::{
     mask::Mask,
-    staged_ledger::{staged_ledger::StagedLedger, diff::Diff},
+    staged_ledger::{
+        staged_ledger::{StagedLedger, StagedLedgerError},
+        diff::Diff,
+    },
     verifier::Verifier,
     scan_state::{
         scan_state::ConstraintConstants,
-        currency::{Amount, Fee},
-        transaction_logic::{local_state::LocalState, protocol_state},
+        transaction_logic::{local_state::LocalState, protocol_state, Transaction},
         self,
     },
     Database, BaseLedger,
 };
 use mina_signer::CompressedPubKey;
 
-pub const CONSTRAINT_CONSTANTS: ConstraintConstants = ConstraintConstants {
-    sub_windows_per_window: 11,
-    ledger_depth: 35,
-    work_delay: 2,
-    block_window_duration_ms: 180000,
-    transaction_capacity_log_2: 7,
-    pending_coinbase_depth: 5,
-    coinbase_amount: Amount::from_u64(720000000000),
-    supercharged_coinbase_factor: 2,
-    account_creation_fee: Fee::from_u64(1000000000),
-    fork: None,
+use crate::{
+    checkpoint::Checkpoints,
+    report::{ApplyResult, BlockReport, Report},
 };
 
+/// Replays every block starting from the checkpoint, or from the root if there is none.
+/// In the first case `blocks` must start at the height of the checkpoint,
+/// the checkpoint is given by the hash of its block.
 pub fn again(
+    constants: &ConstraintConstants,
     accounts: Vec<v2::MinaBaseAccountBinableArgStableV2>,
     aux: Aux,
     mut blocks: impl Iterator<Item = Vec<v2::MinaBlockBlockStableV2>>,
-) {
+    checkpoints: Option<&Checkpoints>,
+    resume: Option<(String, Storage)>,
+) -> Report {
     let root_blocks = blocks.next().unwrap();
-    let root_block = root_blocks.first().unwrap();
+    let (root_block, result, storage) = match resume {
+        Some((hash, mut storage)) => {
+            let root_block = root_blocks
+                .iter()
+                .find(|b| b.hash().to_string() == hash)
+                .expect("the checkpoint is on the chain");
+            log::info!("resume from the checkpoint {hash}");
+            let expected_hash = &root_block
+                .header
+                .protocol_state
+                .body
+                .blockchain_state
+                .staged_ledger_hash;
+            (root_block, storage.check(expected_hash), Some(storage))
+        }
+        None => {
+            let root_block = root_blocks.first().unwrap();
+            let snarked_ledger = ledger(constants, accounts);
+            let expected_hash = root_block
+                .header
+                .protocol_state
+                .body
+                .blockchain_state
+                .staged_ledger_hash
+                .clone();
+            let (result, storage) =
+                Storage::new(constants.clone(), snarked_ledger, aux, expected_hash);
+            (root_block, result, storage)
+        }
+    };
     let root_block_hash = root_block.hash();
-
     let last_protocol_state = root_block.header.protocol_state.clone();
 
-    let mut snarked_ledger = Mask::new_root(Database::create(35));
-    for account in accounts {
-        let account = mina_tree::Account::from(&account);
-        let account_id = account.id();
-        snarked_ledger
-            .get_or_create_account(account_id, account)
-            .unwrap();
-    }
-
-    let _ = snarked_ledger.merkle_root();
-
-    let info = aux;
-
-    let expected_hash = last_protocol_state
-        .body
-        .blockchain_state
-        .staged_ledger_hash
-        .clone();
-    let storage = Storage::new(snarked_ledger, info, expected_hash);
+    let mut report = Report::new(BlockReport {
+        height: last_protocol_state
+            .body
+            .consensus_state
+            .blockchain_length
+            .as_u32(),
+        hash: root_block_hash.clone(),
+        result,
+    });
+    // cannot continue without the staged ledger of the root
+    let Some(storage) = storage else {
+        return report;
+    };
 
     log::info!("obtain staged ledger");
 
@@ -89,68 +111,156 @@ pub fn again(
                     .blockchain_length
                     .as_u32();
                 log::info!("will apply: {} prev: {prev_hash}, this: {hash}", height);
-                storage.apply_block(&block, &prev_protocol_state);
-                new_ancestors.insert(hash, (block.header.protocol_state.clone(), storage));
+                let result = storage.apply_block(&block, &prev_protocol_state);
+                if result.is_ok() {
+                    if let Some(checkpoints) = checkpoints {
+                        // the replay goes on without the checkpoint
+                        if let Err(err) = checkpoints.save(height, &hash.to_string(), &storage) {
+                            log::warn!("cannot save the checkpoint at {height} {hash}: {err}");
+                        }
+                    }
+                }
+                // after a hash mismatch the staged ledger is still usable,
+                // so the descendants are applied to find out whether it propagates
+                if !matches!(result, ApplyResult::Error { .. }) {
+                    new_ancestors
+                        .insert(hash.clone(), (block.header.protocol_state.clone(), storage));
+                }
+                report.push(BlockReport {
+                    height,
+                    hash,
+                    result,
+                });
             }
         }
         ancestors = new_ancestors;
     }
+
+    report
 }
 
+pub fn ledger(
+    constants: &ConstraintConstants,
+    accounts: Vec<v2::MinaBaseAccountBinableArgStableV2>,
+) -> Mask {
+    let mut ledger = Mask::new_root(Database::create(constants.ledger_depth as _));
+    for account in accounts {
+        let account = mina_tree::Account::from(&account);
+        let account_id = account.id();
+        ledger.get_or_create_account(account_id, account).unwrap();
+    }
+    let _ = ledger.merkle_root();
+
+    ledger
+}
+
+/// The accounts of the staged ledger, the scan state and the pending coinbase.
+pub type Snapshot = (
+    Vec<v2::MinaBaseAccountBinableArgStableV2>,
+    v2::TransactionSnarkScanStateStableV2,
+    v2::MinaBasePendingCoinbaseStableV2,
+);
+
 #[derive(Clone)]
 pub struct Storage {
+    constants: ConstraintConstants,
     staged_ledger: StagedLedger,
 }
 
 impl Storage {
     pub fn new(
+        constants: ConstraintConstants,
         snarked_ledger: Mask,
         info: Aux,
         expected_hash: v2::MinaBaseStagedLedgerHashStableV1,
-    ) -> Self {
-        // TODO: fix for genesis block
-        let (scan_state, expected_ledger_hash, pending_coinbase, states) = info.unwrap();
+    ) -> (ApplyResult, Option<Self>) {
+        let staged_ledger = match info {
+            // the genesis block, its scan state is empty
+            None => StagedLedger::create_exn(constants.clone(), snarked_ledger),
+            Some((scan_state, expected_ledger_hash, pending_coinbase, states)) => {
+                let states = states
+                    .into_iter()
+                    .map(|state| (state.hash().to_fp().unwrap(), state))
+                    .collect::<BTreeMap<_, _>>();
 
-        let states = states
-            .into_iter()
-            .map(|state| (state.hash().to_fp().unwrap(), state))
-            .collect::<BTreeMap<_, _>>();
+                StagedLedger::of_scan_state_pending_coinbases_and_snarked_ledger(
+                    (),
+                    &constants,
+                    Verifier,
+                    (&scan_state).into(),
+                    snarked_ledger.clone(),
+                    LocalState::empty(),
+                    expected_ledger_hash.clone().into(),
+                    (&pending_coinbase).into(),
+                    |key| states.get(&key).cloned().unwrap(),
+                )
+            }
+        };
+        let staged_ledger = match staged_ledger {
+            Ok(v) => v,
+            Err(err) => return (ApplyResult::error(err, None), None),
+        };
 
-        let mut staged_ledger = StagedLedger::of_scan_state_pending_coinbases_and_snarked_ledger(
-            (),
-            &CONSTRAINT_CONSTANTS,
-            Verifier,
+        let mut storage = Storage {
+            constants,
+            staged_ledger,
+        };
+        (storage.check(&expected_hash), Some(storage))
+    }
+
+    /// Restores the state saved by `snapshot`.
+    pub fn from_snapshot(constants: ConstraintConstants, snapshot: Snapshot) -> Self {
+        let (accounts, scan_state, pending_coinbase) = snapshot;
+        // the staged ledger is flattened, it has the same accounts at the same positions
+        let staged_ledger = StagedLedger::of_scan_state_and_ledger_unchecked(
+            ledger(&constants, accounts),
             (&scan_state).into(),
-            snarked_ledger.clone(),
-            LocalState::empty(),
-            expected_ledger_hash.clone().into(),
+            constants.clone(),
             (&pending_coinbase).into(),
-            |key| states.get(&key).cloned().unwrap(),
         )
         .unwrap();
 
-        let expected_hash_str = serde_json::to_string(&expected_hash).unwrap();
-        log::info!("expected staged ledger hash: {expected_hash_str}");
+        Storage {
+            constants,
+            staged_ledger,
+        }
+    }
 
-        let actual_hash = v2::MinaBaseStagedLedgerHashStableV1::from(&staged_ledger.hash());
-        let actual_hash_str = serde_json::to_string(&actual_hash).unwrap();
-        log::info!("actual staged ledger hash {actual_hash_str}");
+    /// Compares the hash of the staged ledger with the expected one.
+    pub fn check(&mut self, expected_hash: &v2::MinaBaseStagedLedgerHashStableV1) -> ApplyResult {
+        let actual_hash = v2::MinaBaseStagedLedgerHashStableV1::from(&self.staged_ledger.hash());
+        ApplyResult::check(expected_hash, &actual_hash)
+    }
 
-        assert_eq!(expected_hash, actual_hash);
+    pub fn snapshot(&self) -> Snapshot {
+        let accounts = self
+            .staged_ledger
+            .ledger()
+            .to_list()
+            .iter()
+            .map(Into::into)
+            .collect();
+        let scan_state = self.staged_ledger.scan_state().into();
+        let pending_coinbase = self.staged_ledger.pending_coinbase_collection().into();
 
-        Storage { staged_ledger }
+        (accounts, scan_state, pending_coinbase)
     }
 
     pub fn apply_block(
         &mut self,
         block: &v2::MinaBlockBlockStableV2,
         prev_protocol_state: &v2::MinaStateProtocolStateValueStableV2,
-    ) {
+    ) -> ApplyResult {
         let previous_state_hash = block.header.protocol_state.previous_state_hash.clone();
         let _previous_state_hash = v2::StateHash::from(v2::DataHashLibStateHashStableV1(
             prev_protocol_state.hash().inner().0.clone(),
         ));
-        assert_eq!(previous_state_hash, _previous_state_hash);
+        if previous_state_hash != _previous_state_hash {
+            return ApplyResult::error(
+                format!("previous state hash {previous_state_hash} != {_previous_state_hash}"),
+                None,
+            );
+        }
 
         let global_slot = block
             .header
@@ -165,41 +275,157 @@ impl Storage {
         let protocol_state = &block.header.protocol_state;
         let consensus_state = &protocol_state.body.consensus_state;
         let coinbase_receiver: CompressedPubKey = (&consensus_state.coinbase_receiver).into();
-        let _supercharge_coinbase = consensus_state.supercharge_coinbase;
-
-        // FIXME: Using `supercharge_coinbase` (from block) above does not work
-        let supercharge_coinbase = false;
+        let supercharge_coinbase = consensus_state.supercharge_coinbase;
 
         let diff: Diff = (&block.body.staged_ledger_diff).into();
+        // to find the index of the failing command
+        let commands = diff.commands();
 
-        let result = self
-            .staged_ledger
-            .apply(
-                None,
-                &CONSTRAINT_CONSTANTS,
-                (&global_slot).into(),
-                diff,
-                (),
-                &Verifier,
-                &prev_state_view,
-                scan_state::protocol_state::hashes(prev_protocol_state),
-                coinbase_receiver,
-                supercharge_coinbase,
-            )
-            .unwrap();
+        let result = self.staged_ledger.apply(
+            None,
+            &self.constants,
+            (&global_slot).into(),
+            diff,
+            (),
+            &Verifier,
+            &prev_state_view,
+            scan_state::protocol_state::hashes(prev_protocol_state),
+            coinbase_receiver,
+            supercharge_coinbase,
+        );
+        let result = match result {
+            Ok(v) => v,
+            Err(StagedLedgerError::MismatchedStatuses(tx, status)) => {
+                let index = commands
+                    .iter()
+                    .position(|command| Transaction::Command(command.data.clone()) == tx.data);
+                return ApplyResult::error(
+                    StagedLedgerError::MismatchedStatuses(tx, status),
+                    index,
+                );
+            }
+            Err(err) => return ApplyResult::error(err, None),
+        };
         let hash = v2::MinaBaseStagedLedgerHashStableV1::from(&result.hash_after_applying);
-        let hash_str = serde_json::to_string(&hash).unwrap();
-        log::info!("new staged ledger hash {hash_str}");
-        let expected_hash_str = serde_json::to_string(
-            &block
-                .header
-                .protocol_state
-                .body
-                .blockchain_state
-                .staged_ledger_hash,
-        )
+        let expected_hash = &block
+            .header
+            .protocol_state
+            .body
+            .blockchain_state
+            .staged_ledger_hash;
+        ApplyResult::check(expected_hash, &hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, io::BufReader, path::PathBuf};
+
+    use mina_p2p_messages::{binprot::BinProtRead, v2};
+    use mina_signer::CompressedPubKey;
+    use mina_tree::{Account, AccountId, TokenId};
+    use openmina_archive_profile::NetworkProfile;
+
+    use super::{ledger, Snapshot, Storage};
+
+    /// The value with every field zero or empty.
+    fn empty<T>() -> T
+    where
+        T: BinProtRead,
+    {
+        let zeros = vec![0; 0x10000];
+        T::binprot_read(&mut zeros.as_slice()).unwrap()
+    }
+
+    /// Applies the block that has only the coinbase on top of the genesis staged ledger
+    /// with the coinbase receiver's account, returns the balance of the receiver after.
+    fn coinbase(supercharge: bool) -> u64 {
+        let constants = NetworkProfile::builtin("berkeley")
+            .unwrap()
+            .constraint_constants();
+
+        let parent = empty::<v2::MinaStateProtocolStateValueStableV2>();
+        let mut block = empty::<v2::MinaBlockBlockStableV2>();
+        block.header.protocol_state.previous_state_hash = v2::StateHash::from(
+            v2::DataHashLibStateHashStableV1(parent.hash().inner().0.clone()),
+        );
+        let consensus_state = &mut block.header.protocol_state.body.consensus_state;
+        consensus_state.supercharge_coinbase = supercharge;
+        let receiver: CompressedPubKey = (&consensus_state.coinbase_receiver).into();
+        let diff = &mut block.body.staged_ledger_diff.diff.0;
+        diff.coinbase =
+            v2::StagedLedgerDiffDiffPreDiffWithAtMostTwoCoinbaseStableV2Coinbase::One(None);
+        diff.internal_command_statuses =
+            std::iter::once(v2::MinaBaseTransactionStatusStableV2::Applied).collect();
+
+        let account = Account::initialize(&AccountId::new(receiver.clone(), TokenId::default()));
+        let snarked_ledger = ledger(&constants, vec![(&account).into()]);
+        let (_, storage) = Storage::new(constants, snarked_ledger, None, empty());
+        let mut storage = storage.unwrap();
+        // the staged ledger hash in the header is zero, only the balance is checked
+        storage.apply_block(&block, &parent);
+
+        let (accounts, _, _) = storage.snapshot();
+        accounts
+            .iter()
+            .map(Account::from)
+            .find(|account| account.public_key == receiver)
+            .unwrap()
+            .balance
+            .as_u64()
+    }
+
+    #[test]
+    fn supercharged_coinbase() {
+        let constants = NetworkProfile::builtin("berkeley")
+            .unwrap()
+            .constraint_constants();
+        let amount = constants.coinbase_amount.as_u64();
+        let factor = constants.supercharged_coinbase_factor as u64;
+
+        assert_eq!(coinbase(false), amount);
+        assert_eq!(coinbase(true), amount * factor);
+    }
+
+    /// Replays the block of the fixture, a directory in the format `bisect` dumps it.
+    /// Returns the `supercharge_coinbase` flag of the block and whether the replay agrees.
+    fn replay_fixture(name: &str) -> (bool, bool) {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join(name);
+        let read = |file: &str| BufReader::new(File::open(path.join(file)).unwrap());
+
+        let block = v2::MinaBlockBlockStableV2::binprot_read(&mut read("block")).unwrap();
+        let parent = v2::MinaStateProtocolStateValueStableV2::binprot_read(&mut read(
+            "parent_protocol_state",
+        ))
         .unwrap();
-        log::info!("expected staged ledger hash {expected_hash_str}");
-        assert_eq!(hash_str, expected_hash_str);
+        let snapshot: Snapshot = BinProtRead::binprot_read(&mut read("pre_state")).unwrap();
+
+        let constants = NetworkProfile::builtin("berkeley")
+            .unwrap()
+            .constraint_constants();
+        let mut storage = Storage::from_snapshot(constants, snapshot);
+        let result = storage.apply_block(&block, &parent);
+
+        let supercharged = block
+            .header
+            .protocol_state
+            .body
+            .consensus_state
+            .supercharge_coinbase;
+        (supercharged, result.is_ok())
+    }
+
+    #[test]
+    #[ignore = "needs fixtures/supercharged, dumped by `tester bisect --head <hash>`"]
+    fn supercharged_block() {
+        assert_eq!(replay_fixture("supercharged"), (true, true));
+    }
+
+    #[test]
+    #[ignore = "needs fixtures/regular, dumped by `tester bisect --head <hash>`"]
+    fn regular_block() {
+        assert_eq!(replay_fixture("regular"), (false, true));
     }
 }
@@ -0,0 +1,103 @@
+use std::{
+    collections::BTreeSet,
+    fs::{self, File},
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use mina_p2p_messages::{
+    binprot::{self, BinProtRead, BinProtWrite},
+    v2,
+};
+use mina_tree::scan_state::scan_state::ConstraintConstants;
+use thiserror::Error;
+
+use crate::bootstrap::Storage;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Binprot(#[from] binprot::Error),
+}
+
+/// The states of the replay every `interval` blocks, a file per block
+/// named `<height>-<state hash>`.
+pub struct Checkpoints {
+    path: PathBuf,
+    interval: u32,
+}
+
+impl Checkpoints {
+    pub fn open(path: PathBuf, interval: u32) -> Self {
+        fs::create_dir_all(&path).unwrap_or_default();
+        Checkpoints { path, interval }
+    }
+
+    /// The height and the hash of the block of every checkpoint, lowest first.
+    pub fn all(&self) -> io::Result<Vec<(u32, String)>> {
+        let mut all = fs::read_dir(&self.path)?
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name().into_string().ok()?;
+                let (height, hash) = name.split_once('-')?;
+                Some((height.parse().ok()?, hash.to_owned()))
+            })
+            .collect::<Vec<_>>();
+        all.sort();
+
+        Ok(all)
+    }
+
+    pub fn load(
+        &self,
+        constants: &ConstraintConstants,
+        height: u32,
+        hash: &str,
+    ) -> Result<Storage, Error> {
+        let file = File::open(self.path.join(format!("{height}-{hash}")))?;
+        let mut file = io::BufReader::new(file);
+        let snapshot = BinProtRead::binprot_read(&mut file)?;
+        Ok(Storage::from_snapshot(constants.clone(), snapshot))
+    }
+
+    /// Stores the state if the height is a multiple of the interval.
+    pub fn save(&self, height: u32, hash: &str, storage: &Storage) -> Result<(), Error> {
+        if self.interval == 0 || height % self.interval != 0 {
+            return Ok(());
+        }
+
+        // write the whole file before it gets the name, a partial checkpoint is never loaded
+        let tmp = self.path.join(format!(".{height}-{hash}"));
+        let mut file = io::BufWriter::new(File::create(&tmp)?);
+        storage.snapshot().binprot_write(&mut file)?;
+        file.flush()?;
+        fs::rename(tmp, self.path.join(format!("{height}-{hash}")))?;
+        log::info!("checkpoint at {height} {hash}");
+
+        Ok(())
+    }
+}
+
+/// Whether the block is a block of the highest level or its ancestor,
+/// a checkpoint of a dead fork is not.
+pub fn on_chain(hash: &str, levels: &[Vec<v2::MinaBlockBlockStableV2>]) -> bool {
+    // the parents of the blocks of the chain in the level above
+    let mut parents = None::<BTreeSet<v2::StateHash>>;
+    for level in levels.iter().rev() {
+        let mut next = BTreeSet::new();
+        for block in level {
+            let block_hash = block.hash();
+            if parents.as_ref().is_some_and(|p| !p.contains(&block_hash)) {
+                continue;
+            }
+            if block_hash.to_string() == hash {
+                return true;
+            }
+            next.insert(block.header.protocol_state.previous_state_hash.clone());
+        }
+        parents = Some(next);
+    }
+
+    false
+}
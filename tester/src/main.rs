@@ -1,19 +1,41 @@
 mod backup;
+mod bisect;
 mod bootstrap;
 mod inspect;
 mod catch;
+mod checkpoint;
+mod report;
 
 use std::{path::PathBuf, time::Duration, io};
 
 use bytes::Bytes;
-use mina_p2p_messages::binprot::BinProtRead;
+use mina_p2p_messages::{binprot::BinProtRead, v2};
+use mina_tree::scan_state::scan_state::ConstraintConstants;
 use structopt::StructOpt;
 use reqwest::{Url, blocking::Client};
 
+use openmina_archive_profile::NetworkProfile;
+
+use bootstrap::Storage;
+use checkpoint::Checkpoints;
+
 #[derive(StructOpt)]
 struct Args {
+    /// The archive to take the root ledger and the blocks from
+    #[structopt(long, required_unless = "from_backup")]
+    url: Option<Url>,
+    /// The directory written by `backup`, instead of the archive
+    #[structopt(long, conflicts_with = "url")]
+    from_backup: Option<PathBuf>,
+    /// The name of a built-in network profile, or the path to the profile file
+    #[structopt(long, default_value = "berkeley")]
+    network: NetworkProfile,
+    /// The directory of the replay checkpoints, `apply` resumes from the latest one on the chain
     #[structopt(long)]
-    url: Url,
+    checkpoints: Option<PathBuf>,
+    /// Store the replay state every that many blocks
+    #[structopt(long, default_value = "1000")]
+    checkpoint_interval: u32,
     #[structopt(subcommand)]
     command: Command,
 }
@@ -30,37 +52,177 @@ enum Command {
         hash: String,
         level: u32,
     },
+    /// Find the first block whose replay disagrees with its header
+    Bisect {
+        /// The last block of the chain, the block of the highest level by default
+        #[structopt(long)]
+        head: Option<String>,
+        /// Where to dump the block, its parent protocol state and the state before it,
+        /// the head if all blocks agree
+        #[structopt(long)]
+        out: PathBuf,
+    },
 }
 
 fn main() {
     env_logger::init();
 
-    let Args { url, command } = Args::from_args();
+    let Args {
+        url,
+        from_backup,
+        network,
+        checkpoints,
+        checkpoint_interval,
+        command,
+    } = Args::from_args();
+    let checkpoints = checkpoints.map(|path| Checkpoints::open(path, checkpoint_interval));
+    let source = match (url, from_backup) {
+        (_, Some(path)) => Source::Backup(path),
+        (Some(url), None) => Source::Http(url),
+        (None, None) => unreachable!("the url is required without the backup"),
+    };
 
     match command {
         Command::Backup { path } => {
-            let (ledger_bytes, blocks) = load(url);
+            let (ledger_bytes, blocks) = source.load(None);
             backup::run(path, &ledger_bytes, blocks)
         }
         Command::Apply => {
-            let (ledger_bytes, blocks) = load(url);
+            let constants = network.constraint_constants();
+            let resumed = checkpoints
+                .as_ref()
+                .and_then(|c| resume(c, &constants, &source));
+            let (ledger_bytes, blocks, resume) = match resumed {
+                Some((ledger_bytes, levels, resume)) => (
+                    ledger_bytes,
+                    Box::new(levels.into_iter()) as Levels,
+                    Some(resume),
+                ),
+                // no usable checkpoint, the replay starts from the root
+                None => {
+                    let (ledger_bytes, blocks) = source.load(None);
+                    (ledger_bytes, decode(blocks), None)
+                }
+            };
             let mut s = ledger_bytes.as_ref();
             let (ledger, aux) = BinProtRead::binprot_read(&mut s).unwrap();
-            bootstrap::again(
+            let report = bootstrap::again(
+                &constants,
                 ledger,
                 aux,
-                blocks.map(|mut reader| BinProtRead::binprot_read(&mut reader).unwrap()),
+                blocks,
+                checkpoints.as_ref(),
+                resume,
             );
+            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+            if !report.is_ok() {
+                std::process::exit(1);
+            }
         }
         Command::Inspect => {
-            let (_, blocks) = load(url);
-            inspect::run(blocks)
+            let (_, blocks) = source.load(None);
+            inspect::run(blocks.map(|(_, reader)| reader))
+        }
+        Command::Catch { hash, level } => {
+            let Source::Http(url) = source else {
+                panic!("catch needs the url");
+            };
+            catch::run(url, hash, level)
+        }
+        Command::Bisect { head, out } => {
+            let (ledger_bytes, blocks) = source.load(None);
+            let mut s = ledger_bytes.as_ref();
+            let (ledger, aux) = BinProtRead::binprot_read(&mut s).unwrap();
+            let result = bisect::run(
+                &network.constraint_constants(),
+                ledger,
+                aux,
+                decode(blocks),
+                head,
+                &out,
+            );
+            match result {
+                Ok(None) => {}
+                Ok(Some(result)) if result.is_ok() => {}
+                Ok(Some(result)) => {
+                    println!("{}", serde_json::to_string_pretty(&result).unwrap());
+                    std::process::exit(1);
+                }
+                Err(err) => {
+                    log::error!("bisect: {err}");
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+}
+
+/// The blocks of every level with the height of the level.
+type Blocks = Box<dyn Iterator<Item = (u32, Box<dyn io::Read>)>>;
+
+/// The decoded blocks of every level.
+type Levels = Box<dyn Iterator<Item = Vec<v2::MinaBlockBlockStableV2>>>;
+
+fn decode(blocks: Blocks) -> Levels {
+    Box::new(blocks.map(|(_, mut reader)| BinProtRead::binprot_read(&mut reader).unwrap()))
+}
+
+/// The root ledger, the levels from the height of the checkpoint,
+/// the hash of the block of the checkpoint and its state.
+type Resumed = (
+    Bytes,
+    Vec<Vec<v2::MinaBlockBlockStableV2>>,
+    (String, Storage),
+);
+
+/// The latest checkpoint that can be loaded and is on the chain being replayed.
+fn resume(
+    checkpoints: &Checkpoints,
+    constants: &ConstraintConstants,
+    source: &Source,
+) -> Option<Resumed> {
+    let all = checkpoints
+        .all()
+        .map_err(|err| log::warn!("cannot list the checkpoints: {err}"))
+        .ok()?;
+    for (height, hash) in all.into_iter().rev() {
+        let storage = match checkpoints.load(constants, height, &hash) {
+            Ok(v) => v,
+            Err(err) => {
+                log::warn!("cannot load the checkpoint at {height} {hash}: {err}");
+                continue;
+            }
+        };
+        let (ledger_bytes, blocks) = source.load(Some(height));
+        let levels = decode(blocks).collect::<Vec<_>>();
+        if !checkpoint::on_chain(&hash, &levels) {
+            log::warn!("the checkpoint at {height} {hash} is not on the chain");
+            continue;
+        }
+        log::info!("resume from the checkpoint at {height} {hash}");
+        return Some((ledger_bytes, levels, (hash, storage)));
+    }
+
+    None
+}
+
+/// Where the root ledger and the blocks come from.
+enum Source {
+    Http(Url),
+    Backup(PathBuf),
+}
+
+impl Source {
+    fn load(&self, from: Option<u32>) -> (Bytes, Blocks) {
+        match self {
+            Source::Http(url) => load(url.clone(), from),
+            Source::Backup(path) => backup::load(path, from),
         }
-        Command::Catch { hash, level } => catch::run(url, hash, level),
     }
 }
 
-fn load(url: Url) -> (Bytes, impl Iterator<Item = impl io::Read>) {
+/// The root ledger and the blocks starting from the root, or from the given height if it is above.
+fn load(url: Url, from: Option<u32>) -> (Bytes, Blocks) {
     let client = Client::builder()
         .timeout(Duration::from_secs(10))
         .build()
@@ -78,14 +240,16 @@ fn load(url: Url) -> (Bytes, impl Iterator<Item = impl io::Read>) {
         .bytes()
         .unwrap();
 
-    let blocks = (root..head).map(move |level| {
+    let start = from.map_or(root, |from| from.max(root));
+    let blocks = (start..head).map(move |level| {
         let url = url
             .join("transitions/")
             .unwrap()
             .join(&level.to_string())
             .unwrap();
-        client.get(url).send().unwrap()
+        let response = client.get(url).send().unwrap();
+        (level, Box::new(response) as Box<dyn io::Read>)
     });
 
-    (ledger_bytes, blocks)
+    (ledger_bytes, Box::new(blocks))
 }
@@ -0,0 +1,107 @@
+use mina_p2p_messages::v2;
+use serde::Serialize;
+
+/// The outcome of constructing the staged ledger of the root, or applying a block.
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ApplyResult {
+    Ok,
+    HashMismatch(HashMismatch),
+    Error {
+        error: String,
+        /// Index of the failing command in the staged ledger diff, if known.
+        transaction: Option<usize>,
+    },
+}
+
+impl ApplyResult {
+    pub fn check(
+        expected: &v2::MinaBaseStagedLedgerHashStableV1,
+        actual: &v2::MinaBaseStagedLedgerHashStableV1,
+    ) -> Self {
+        let mut mismatched = vec![];
+        if expected.non_snark.ledger_hash != actual.non_snark.ledger_hash {
+            mismatched.push("ledger_hash");
+        }
+        if expected.non_snark.aux_hash != actual.non_snark.aux_hash {
+            mismatched.push("aux_hash");
+        }
+        if expected.non_snark.pending_coinbase_aux != actual.non_snark.pending_coinbase_aux {
+            mismatched.push("pending_coinbase_aux");
+        }
+        if expected.pending_coinbase_hash != actual.pending_coinbase_hash {
+            mismatched.push("pending_coinbase_hash");
+        }
+
+        if mismatched.is_empty() {
+            ApplyResult::Ok
+        } else {
+            ApplyResult::HashMismatch(HashMismatch {
+                expected: expected.clone(),
+                actual: actual.clone(),
+                mismatched,
+            })
+        }
+    }
+
+    pub fn error<E>(error: E, transaction: Option<usize>) -> Self
+    where
+        E: std::fmt::Debug,
+    {
+        ApplyResult::Error {
+            error: format!("{error:?}"),
+            transaction,
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        matches!(self, ApplyResult::Ok)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct HashMismatch {
+    pub expected: v2::MinaBaseStagedLedgerHashStableV1,
+    pub actual: v2::MinaBaseStagedLedgerHashStableV1,
+    /// Names of the components of the staged ledger hash that differ.
+    pub mismatched: Vec<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BlockReport {
+    pub height: u32,
+    pub hash: v2::StateHash,
+    pub result: ApplyResult,
+}
+
+/// The report of the replay, printed as json.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub root: BlockReport,
+    pub blocks: Vec<BlockReport>,
+    pub applied: usize,
+    pub diverged: usize,
+}
+
+impl Report {
+    pub fn new(root: BlockReport) -> Self {
+        Report {
+            root,
+            blocks: vec![],
+            applied: 0,
+            diverged: 0,
+        }
+    }
+
+    pub fn push(&mut self, block: BlockReport) {
+        self.applied += 1;
+        if !block.result.is_ok() {
+            self.diverged += 1;
+        }
+        self.blocks.push(block);
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.root.result.is_ok() && self.diverged == 0
+    }
+}